@@ -0,0 +1,59 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Sets `HEALTH_NOTIFY_VERSION` for `--version`:
+/// the crate version, followed by the commit and its date when known.
+/// Builds outside a Git checkout (like Nix's) can pass the commit
+/// in `HEALTH_NOTIFY_BUILD_COMMIT` instead.
+fn main() {
+    println!("cargo:rerun-if-env-changed=HEALTH_NOTIFY_BUILD_COMMIT");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+
+    let mut version = env::var("CARGO_PKG_VERSION").unwrap();
+    let commit = env::var("HEALTH_NOTIFY_BUILD_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .map(|commit| (commit, None))
+        .or_else(|| {
+            let commit = git(&["rev-parse", "--short=12", "HEAD"])?;
+            let date = git(&["log", "-1", "--format=%cs", "HEAD"]);
+            Some((commit, date))
+        });
+    match commit {
+        Some((commit, Some(date))) => version += &format!(" ({} {})", commit, date),
+        Some((commit, None)) => version += &format!(" ({})", commit),
+        None => {}
+    }
+    println!("cargo:rustc-env=HEALTH_NOTIFY_VERSION={}", version);
+}
+
+/// Run a Git command and return its trimmed output,
+/// or `None` if Git isn't available or the command fails.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let s = String::from_utf8(output.stdout).ok()?;
+    Some(s.trim().to_string())
+}
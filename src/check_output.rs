@@ -0,0 +1,157 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::process::Child;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum number of bytes of output retained per check.
+const MAX_LEN: usize = 8 * 1024;
+
+/// How long to wait for the output pipes to close after the check exits.
+/// Background processes started by the check may keep them open indefinitely.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// `CheckOutput` collects the last few kilobytes that a health check
+/// writes to its standard output and standard error.
+/// The check must have been spawned with both streams piped.
+#[derive(Debug)]
+pub(crate) struct CheckOutput {
+    tail: Arc<Mutex<Tail>>,
+    done: mpsc::Receiver<()>,
+    streams: usize,
+}
+
+#[derive(Debug, Default)]
+struct Tail {
+    buf: VecDeque<u8>,
+    truncated: bool,
+}
+
+impl CheckOutput {
+    /// Start reading the piped output streams of `check_child` in the background.
+    pub(crate) fn capture(check_child: &mut Child) -> Self {
+        let tail = Arc::new(Mutex::new(Tail::default()));
+        let (done_tx, done) = mpsc::channel();
+        let mut streams = 0;
+        if let Some(stdout) = check_child.stdout.take() {
+            spawn_reader(stdout, tail.clone(), done_tx.clone());
+            streams += 1;
+        }
+        if let Some(stderr) = check_child.stderr.take() {
+            spawn_reader(stderr, tail.clone(), done_tx);
+            streams += 1;
+        }
+        CheckOutput {
+            tail,
+            done,
+            streams,
+        }
+    }
+
+    /// Write the collected output to our standard error,
+    /// prefixing each line with `[check]`.
+    /// The check should have exited before calling `report`.
+    pub(crate) fn report(self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        for _ in 0..self.streams {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(timeout).is_err() {
+                break;
+            }
+        }
+
+        let tail = self.tail.lock().unwrap();
+        let (front, back) = tail.buf.as_slices();
+        let text = String::from_utf8_lossy(&[front, back].concat()).into_owned();
+        let mut stderr = io::stderr().lock();
+        if tail.truncated {
+            let _ = writeln!(stderr, "[check] ...");
+        }
+        for line in text.lines() {
+            let _ = writeln!(stderr, "[check] {}", line);
+        }
+    }
+}
+
+fn spawn_reader(
+    mut stream: impl Read + Send + 'static,
+    tail: Arc<Mutex<Tail>>,
+    done: mpsc::Sender<()>,
+) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => tail.lock().unwrap().push(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        let _ = done.send(());
+    });
+}
+
+impl Tail {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        if self.buf.len() > MAX_LEN {
+            self.buf.drain(..self.buf.len() - MAX_LEN);
+            self.truncated = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(tail: &Tail) -> Vec<u8> {
+        tail.buf.iter().copied().collect()
+    }
+
+    #[test]
+    fn push_keeps_short_output() {
+        let mut tail = Tail::default();
+        tail.push(b"hello\n");
+        tail.push(b"world\n");
+        assert_eq!(contents(&tail), b"hello\nworld\n");
+        assert!(!tail.truncated);
+    }
+
+    #[test]
+    fn push_keeps_only_the_tail() {
+        let mut tail = Tail::default();
+        tail.push(&[b'a'; MAX_LEN - 1]);
+        assert!(!tail.truncated);
+        tail.push(b"bcd");
+        assert!(tail.truncated);
+        let kept = contents(&tail);
+        assert_eq!(kept.len(), MAX_LEN);
+        assert!(kept.starts_with(b"aaa"));
+        assert!(kept.ends_with(b"abcd"));
+
+        // A single write longer than the limit keeps only its own end.
+        let long = (0..2 * MAX_LEN).map(|i| i as u8).collect::<Vec<_>>();
+        tail.push(&long);
+        assert_eq!(contents(&tail), &long[MAX_LEN..]);
+        assert!(tail.truncated);
+    }
+}
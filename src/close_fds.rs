@@ -0,0 +1,173 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use nix::fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag};
+use nix::libc;
+use nix::sys::stat::Mode;
+
+/// First descriptor after standard input, output, and error.
+const FIRST_FD: RawFd = 3;
+
+/// Configure `command` so that the program it runs
+/// inherits only standard input, output, and error.
+///
+/// Descriptors are marked close-on-exec rather than closed:
+/// the standard library reports exec failures through a close-on-exec pipe
+/// that must stay open until exec succeeds.
+pub(crate) fn close_on_exec(command: &mut Command) {
+    unsafe {
+        command.pre_exec(mark_all);
+    }
+}
+
+/// Mark every descriptor above standard error close-on-exec.
+/// This runs between fork and exec, so it must not allocate.
+fn mark_all() -> io::Result<()> {
+    // SAFETY: close_range(2) only changes descriptor flags.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            FIRST_FD as libc::c_uint,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    // Kernels before 5.11 don't support CLOSE_RANGE_CLOEXEC.
+    match nix::Error::last() {
+        nix::Error::ENOSYS | nix::Error::EINVAL => {}
+        e => return Err(e.into()),
+    }
+    match open(
+        "/proc/self/fd",
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        // SAFETY: open returned a new descriptor that we own.
+        Ok(dir) => mark_listed(unsafe { OwnedFd::from_raw_fd(dir) }),
+        // Without /proc, try every descriptor that could be open.
+        Err(_) => {
+            // SAFETY: sysconf has no preconditions.
+            let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+            let max = RawFd::try_from(max).unwrap_or(RawFd::MAX);
+            for fd in FIRST_FD..max {
+                mark(fd)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Mark the descriptors listed in `dir` (a handle to `/proc/self/fd`).
+/// `dir` itself is already close-on-exec.
+fn mark_listed(dir: OwnedFd) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        // SAFETY: buf is writable for its full length.
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        // Each record is a struct linux_dirent64:
+        // an 8-byte inode, an 8-byte offset, a 2-byte record length,
+        // a 1-byte type, and a NUL-terminated name.
+        let mut records = &buf[..n as usize];
+        while records.len() >= 19 {
+            let len = u16::from_ne_bytes([records[16], records[17]]) as usize;
+            if len < 19 || len > records.len() {
+                break;
+            }
+            if let Some(fd) = parse_fd(&records[19..len]) {
+                if fd >= FIRST_FD {
+                    mark(fd)?;
+                }
+            }
+            records = &records[len..];
+        }
+    }
+}
+
+/// Parse a NUL-terminated decimal descriptor number.
+/// Returns `None` for `.` and `..`.
+fn parse_fd(name: &[u8]) -> Option<RawFd> {
+    let digits = name.split(|&b| b == 0).next()?;
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0 as RawFd, |fd, &b| {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        fd.checked_mul(10)?.checked_add(RawFd::from(b - b'0'))
+    })
+}
+
+fn mark(fd: RawFd) -> io::Result<()> {
+    match fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        Ok(_) | Err(nix::Error::EBADF) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use super::*;
+
+    #[test]
+    fn child_inherits_only_standard_fds() {
+        // A descriptor that would otherwise be inherited.
+        let (leaked, _other) = std::os::unix::net::UnixStream::pair().unwrap();
+        fcntl(leaked.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        let list_fds = || {
+            let mut command = Command::new("sh");
+            command
+                .args(["-c", "ls /proc/$$/fd"])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null());
+            command
+        };
+
+        let output = list_fds().output().unwrap();
+        let fds = String::from_utf8(output.stdout).unwrap();
+        let leaked_fd = leaked.as_raw_fd().to_string();
+        assert!(fds.lines().any(|fd| fd == leaked_fd), "{:?}", fds);
+
+        let mut command = list_fds();
+        close_on_exec(&mut command);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "0\n1\n2\n");
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reading `--config` files.
+//!
+//! Config files are TOML documents of top-level `key = value` pairs
+//! where values are strings, integers, booleans, or arrays of those.
+//! Keys are long option names (without the leading `--`),
+//! plus `child` and `check` for the two programs' argument vectors.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ArgAction;
+use toml::{Spanned, Value};
+
+/// The contents of a config file.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// `child`: the child program's argument vector.
+    pub(crate) child: Vec<OsString>,
+    /// `check`: the check program's argument vector.
+    pub(crate) check: Vec<OsString>,
+    /// Every other key translated to command-line arguments,
+    /// each paired with the ID of the option it belongs to.
+    args: Vec<(String, OsString)>,
+}
+
+impl Config {
+    /// Read the config file at `path`,
+    /// translating its options into arguments accepted by `command`.
+    pub(crate) fn read(path: &Path, command: &clap::Command) -> Result<Self> {
+        let src = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let table = toml::from_str::<BTreeMap<Spanned<String>, Value>>(&src).map_err(|e| {
+            let line = e.span().map_or(1, |span| line_number(&src, span));
+            let message = e.message().trim();
+            let message = if message.is_empty() {
+                "invalid syntax".to_string()
+            } else {
+                message.replace('\n', ": ")
+            };
+            anyhow!("{}:{}: {}", path.display(), line, message)
+        })?;
+        // Apply the options in the order they appear in the file.
+        let mut entries = table.into_iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(key, _)| key.span().start);
+        let mut config = Config::default();
+        for (key, value) in entries {
+            config.add(command, key.get_ref(), &value).map_err(|e| {
+                let line = line_number(&src, key.span());
+                anyhow!("{}:{}: {}: {}", path.display(), line, key.get_ref(), e)
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Return the command-line arguments for the config file's options,
+    /// leaving out those for which `given(id)` is true
+    /// so that the command line can replace them.
+    pub(crate) fn args<'a>(
+        &'a self,
+        given: impl Fn(&str) -> bool + 'a,
+    ) -> impl Iterator<Item = &'a OsString> + 'a {
+        self.args
+            .iter()
+            .filter(move |(id, _)| !given(id))
+            .map(|(_, arg)| arg)
+    }
+
+    fn add(&mut self, command: &clap::Command, key: &str, value: &Value) -> Result<()> {
+        match key {
+            "child" => {
+                self.child = argv(value)?;
+                return Ok(());
+            }
+            "check" => {
+                self.check = argv(value)?;
+                return Ok(());
+            }
+            "config" => bail!("config files can't include other config files"),
+            _ if value.is_table() => bail!("tables are not supported"),
+            "separator" => bail!("--separator can only be given on the command line"),
+            _ => {}
+        }
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key))
+            .ok_or_else(|| anyhow!("unknown option"))?;
+        let flag = format!("--{}", key);
+        if let Some(terminator) = arg.get_value_terminator() {
+            // Options like --also-check take argument vectors terminated by ';'
+            // (or --separator).
+            if !matches!(arg.get_action(), ArgAction::Append) {
+                self.push(arg, OsString::from(&flag));
+                self.extend(arg, argv(value)?);
+                self.push(arg, terminator.into());
+                return Ok(());
+            }
+            let Value::Array(values) = value else {
+                bail!("expected an array of arrays of strings");
+            };
+            for value in values {
+                self.push(arg, OsString::from(&flag));
+                self.extend(arg, argv(value)?);
+                self.push(arg, terminator.into());
+            }
+            return Ok(());
+        }
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Boolean(b)) => {
+                if *b {
+                    self.push(arg, flag.into());
+                }
+            }
+            (ArgAction::SetTrue, _) => bail!("expected a boolean"),
+            (ArgAction::Count, Value::Integer(n)) => {
+                let n = usize::try_from(*n).map_err(|_| anyhow!("expected a count"))?;
+                self.extend(arg, (0..n).map(|_| OsString::from(&flag)));
+            }
+            (ArgAction::Count, _) => bail!("expected an integer"),
+            (ArgAction::Append, Value::Array(values)) => {
+                for value in values {
+                    self.push(arg, format!("{}={}", flag, scalar(value)?).into());
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, value) => {
+                self.push(arg, format!("{}={}", flag, scalar(value)?).into());
+            }
+            _ => bail!("unknown option"),
+        }
+        Ok(())
+    }
+
+    /// Add arguments for `arg`.
+    fn extend(&mut self, arg: &clap::Arg, values: impl IntoIterator<Item = OsString>) {
+        let id = arg.get_id().as_str();
+        self.args
+            .extend(values.into_iter().map(|value| (id.to_string(), value)));
+    }
+
+    /// Add one argument for `arg`.
+    fn push(&mut self, arg: &clap::Arg, value: OsString) {
+        self.extend(arg, [value]);
+    }
+}
+
+/// Returns the 1-based line number of the start of `span` in `src`.
+fn line_number(src: &str, span: Range<usize>) -> usize {
+    1 + src.as_bytes()[..span.start.min(src.len())]
+        .iter()
+        .filter(|&&b| b == b'\n')
+        .count()
+}
+
+/// Convert an array of strings to an argument vector.
+fn argv(value: &Value) -> Result<Vec<OsString>> {
+    let Value::Array(values) = value else {
+        bail!("expected an array of strings");
+    };
+    if values.is_empty() {
+        bail!("must not be empty");
+    }
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(OsString::from(s)),
+            _ => Err(anyhow!("expected an array of strings")),
+        })
+        .collect()
+}
+
+/// Format a string, integer, or boolean as an option value.
+fn scalar(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Array(_) => Err(anyhow!("expected a single value")),
+        Value::Float(_) | Value::Datetime(_) | Value::Table(_) => {
+            Err(anyhow!("expected a string, integer, or boolean"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use clap::Arg;
+
+    use super::*;
+
+    /// A command with one option of each kind that config files can set.
+    fn command() -> clap::Command {
+        clap::Command::new("test")
+            .arg(Arg::new("name").long("name").action(ArgAction::Set))
+            .arg(Arg::new("flag").long("flag").action(ArgAction::SetTrue))
+            .arg(Arg::new("verbose").long("verbose").action(ArgAction::Count))
+            .arg(Arg::new("env").long("env").action(ArgAction::Append))
+            .arg(
+                Arg::new("pre_check")
+                    .long("pre-check")
+                    .num_args(1..)
+                    .value_terminator(";")
+                    .action(ArgAction::Set),
+            )
+            .arg(
+                Arg::new("also_check")
+                    .long("also-check")
+                    .num_args(1..)
+                    .value_terminator(";")
+                    .action(ArgAction::Append),
+            )
+    }
+
+    /// Read `src` as a config file.
+    /// Errors have the file name removed.
+    fn read(name: &str, src: &str) -> Result<Config, String> {
+        let path = env::temp_dir().join(format!(
+            "health-notify-{}-config-{}.toml",
+            process::id(),
+            name
+        ));
+        fs::write(&path, src).unwrap();
+        let result = Config::read(&path, &command());
+        fs::remove_file(&path).unwrap();
+        result.map_err(|e| {
+            let prefix = format!("{}:", path.display());
+            e.to_string().strip_prefix(&prefix).unwrap().to_string()
+        })
+    }
+
+    fn args(config: &Config) -> Vec<&str> {
+        config
+            .args(|_| false)
+            .map(|arg| arg.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn value_types() {
+        let config = read(
+            "types",
+            r#"
+# A comment.
+child = ["sleep", "60"]  # Trailing comment.
+check = [
+    "true",
+]
+name = "server"
+flag = true
+verbose = 2
+env = ["A=1", 'B=2', 3]
+pre-check = ["sh", "-c", "exit 0"]
+also-check = [["true"], ["test", "-e", "/"]]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.child, ["sleep", "60"]);
+        assert_eq!(config.check, ["true"]);
+        assert_eq!(
+            args(&config),
+            [
+                "--name=server",
+                "--flag",
+                "--verbose",
+                "--verbose",
+                "--env=A=1",
+                "--env=B=2",
+                "--env=3",
+                "--pre-check",
+                "sh",
+                "-c",
+                "exit 0",
+                ";",
+                "--also-check",
+                "true",
+                ";",
+                "--also-check",
+                "test",
+                "-e",
+                "/",
+                ";",
+            ]
+        );
+    }
+
+    #[test]
+    fn scalars() {
+        let config = read("scalars", "name = 1_000\nflag = false\nverbose = 0\n").unwrap();
+        assert_eq!(args(&config), ["--name=1000"]);
+        let config = read("int", "name = -5").unwrap();
+        assert_eq!(args(&config), ["--name=-5"]);
+        let config = read("bool", "name = true\r\n").unwrap();
+        assert_eq!(args(&config), ["--name=true"]);
+        let config = read("quoted_key", "\"name\" = 'x'\n'flag' = true").unwrap();
+        assert_eq!(args(&config), ["--name=x", "--flag"]);
+    }
+
+    #[test]
+    fn strings() {
+        let config = read(
+            "strings",
+            r#"env = ["a\"b", "c\\d", "\n\t\r", "\u00e9\U0001F980", 'C:\dir', "☃"]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            args(&config),
+            [
+                "--env=a\"b",
+                "--env=c\\d",
+                "--env=\n\t\r",
+                "--env=é🦀",
+                "--env=C:\\dir",
+                "--env=☃",
+            ]
+        );
+    }
+
+    #[test]
+    fn args_leave_out_given_options() {
+        let config = read(
+            "given",
+            "name = 'x'\nenv = ['A=1']\nalso-check = [['true']]\nverbose = 1",
+        )
+        .unwrap();
+        let given = ["env", "also_check"];
+        let args = config
+            .args(|id| given.contains(&id))
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(args, ["--name=x", "--verbose"]);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let cases = [
+            (
+                "name = 'x'\nname = 'y'",
+                "2: duplicate key `name` in document root",
+            ),
+            (
+                "\n\nname = 'x'\nname = 1",
+                "4: duplicate key `name` in document root",
+            ),
+            ("name 'x'", "1: expected `.`, `=`"),
+            ("name = 'x' 'y'", "1: expected newline, `#`"),
+            ("= 'x'", "1: invalid key"),
+            ("name = yes", "1: invalid string: expected `\"`, `'`"),
+            ("name =", "1: invalid syntax"),
+            ("env = ['a' 'b']", "1: invalid array: expected `]`"),
+            ("name = \"x\ny\"", "1: invalid basic string"),
+            ("name = 'x", "1: invalid literal string"),
+            ("\n\"name = 1", "2: invalid basic string"),
+            (
+                "name = \"\\uD800\"",
+                "1: invalid unicode 4-digit hex code: value is out of range",
+            ),
+        ];
+        for (i, (src, want)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                read(&format!("syntax{}", i), src).unwrap_err(),
+                want,
+                "{:?}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn option_errors() {
+        let cases = [
+            (
+                "config = 'x'",
+                "1: config: config files can't include other config files",
+            ),
+            (
+                "separator = '--'",
+                "1: separator: --separator can only be given on the command line",
+            ),
+            ("bogus = 1", "1: bogus: unknown option"),
+            ("[name]", "1: name: tables are not supported"),
+            ("name.first = 'x'", "1: name: tables are not supported"),
+            (
+                "name = { first = 'x' }",
+                "1: name: tables are not supported",
+            ),
+            (
+                "name = 1.5",
+                "1: name: expected a string, integer, or boolean",
+            ),
+            (
+                "env = [1979-05-27]",
+                "1: env: expected a string, integer, or boolean",
+            ),
+            ("flag = 1", "1: flag: expected a boolean"),
+            ("verbose = -1", "1: verbose: expected a count"),
+            ("verbose = true", "1: verbose: expected an integer"),
+            ("name = ['x']", "1: name: expected a single value"),
+            ("env = [['x']]", "1: env: expected a single value"),
+            ("child = 'sleep'", "1: child: expected an array of strings"),
+            ("child = [1]", "1: child: expected an array of strings"),
+            ("check = []", "1: check: must not be empty"),
+            (
+                "pre-check = 'true'",
+                "1: pre-check: expected an array of strings",
+            ),
+            (
+                "also-check = ['true']",
+                "1: also-check: expected an array of strings",
+            ),
+            (
+                "also-check = 'true'",
+                "1: also-check: expected an array of arrays of strings",
+            ),
+            ("\n\nname = 'x'\nbogus = 1", "4: bogus: unknown option"),
+        ];
+        for (i, (src, want)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                read(&format!("option{}", i), src).unwrap_err(),
+                want,
+                "{:?}",
+                src
+            );
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Holding `--defer-signal` signals until the child program is ready.
+//!
+//! A signal that arrives more than once while held is delivered only once,
+//! from its most recent sender.
+//! Held signals are delivered in the order they last arrived.
+
+use std::sync::Mutex;
+
+use signal_hook::low_level::siginfo::Origin;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    released: false,
+    pending: Vec::new(),
+});
+
+struct State {
+    released: bool,
+    pending: Vec<Origin>,
+}
+
+/// Hold `origin` for later delivery.
+/// Returns false without holding it if signals were already [released](release).
+pub(crate) fn hold(origin: &Origin) -> bool {
+    let mut state = STATE.lock().unwrap();
+    if state.released {
+        return false;
+    }
+    state.pending.retain(|held| held.signal != origin.signal);
+    state.pending.push(origin.clone());
+    true
+}
+
+/// Stop holding signals and return the ones held so far.
+pub(crate) fn release() -> Vec<Origin> {
+    let mut state = STATE.lock().unwrap();
+    state.released = true;
+    std::mem::take(&mut state.pending)
+}
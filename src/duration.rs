@@ -0,0 +1,115 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+
+/// Parse a duration given as a whole number followed by a unit:
+/// `ms`, `s`, `m`, or `h`.
+/// A number without a unit is a number of seconds.
+pub(crate) fn parse(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    if number.is_empty() {
+        return Err(format!("{:?}: expected a number followed by a unit", s));
+    }
+    let n = number
+        .parse::<u64>()
+        .map_err(|_| format!("{:?}: number out of range", s))?;
+    let duration = match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => {
+            return Err(format!(
+                "{:?}: unknown unit {:?} (expected ms, s, m, or h)",
+                s, unit
+            ))
+        }
+    };
+    duration.ok_or_else(|| format!("{:?}: number out of range", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse("0"), Ok(Duration::ZERO));
+        assert_eq!(parse("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse("1h"), Ok(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn rejects_missing_number() {
+        assert_eq!(
+            parse(""),
+            Err("\"\": expected a number followed by a unit".to_string())
+        );
+        assert_eq!(
+            parse("s"),
+            Err("\"s\": expected a number followed by a unit".to_string())
+        );
+        assert_eq!(
+            parse("-1s"),
+            Err("\"-1s\": expected a number followed by a unit".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_units() {
+        assert_eq!(
+            parse("5d"),
+            Err("\"5d\": unknown unit \"d\" (expected ms, s, m, or h)".to_string())
+        );
+        assert_eq!(
+            parse("1.5s"),
+            Err("\"1.5s\": unknown unit \".5s\" (expected ms, s, m, or h)".to_string())
+        );
+        assert_eq!(
+            parse("5 s"),
+            Err("\"5 s\": unknown unit \" s\" (expected ms, s, m, or h)".to_string())
+        );
+        assert_eq!(
+            parse("5S"),
+            Err("\"5S\": unknown unit \"S\" (expected ms, s, m, or h)".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(
+            parse("18446744073709551615"),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+        assert_eq!(
+            parse("18446744073709551616"),
+            Err("\"18446744073709551616\": number out of range".to_string())
+        );
+        assert_eq!(
+            parse("18446744073709551615m"),
+            Err("\"18446744073709551615m\": number out of range".to_string())
+        );
+        assert_eq!(
+            parse("5124095576030432h"),
+            Err("\"5124095576030432h\": number out of range".to_string())
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Replace `${VAR}` and `$VAR` in `arg` with the value of the environment variable,
+/// as for `--expand-env`.
+/// Unset variables expand to nothing, `$$` is a literal `$`,
+/// and a `$` not followed by a variable name is left as is.
+pub(crate) fn expand(arg: &OsStr) -> Result<OsString, String> {
+    let mut s = arg.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    while let Some(i) = s.iter().position(|&b| b == b'$') {
+        out.extend_from_slice(&s[..i]);
+        s = &s[i + 1..];
+        let (name, rest) = match s.first() {
+            Some(b'$') => {
+                out.push(b'$');
+                s = &s[1..];
+                continue;
+            }
+            Some(b'{') => {
+                let end = s
+                    .iter()
+                    .position(|&b| b == b'}')
+                    .ok_or_else(|| format!("{:?}: unterminated ${{", arg.to_string_lossy()))?;
+                let name = &s[1..end];
+                if name.is_empty() || name_len(name) != name.len() {
+                    return Err(format!(
+                        "{:?}: bad variable name {:?}",
+                        arg.to_string_lossy(),
+                        String::from_utf8_lossy(name)
+                    ));
+                }
+                (name, &s[end + 1..])
+            }
+            _ => s.split_at(name_len(s)),
+        };
+        if name.is_empty() {
+            out.push(b'$');
+            continue;
+        }
+        if let Some(value) = env::var_os(OsStr::from_bytes(name)) {
+            out.extend_from_slice(value.as_bytes());
+        }
+        s = rest;
+    }
+    out.extend_from_slice(s);
+    Ok(OsString::from_vec(out))
+}
+
+/// Expand every element of `argv` in place.
+pub(crate) fn expand_all(argv: &mut [OsString]) -> Result<(), String> {
+    for arg in argv {
+        *arg = expand(arg)?;
+    }
+    Ok(())
+}
+
+/// Returns the length of the shell variable name at the start of `s`.
+fn name_len(s: &[u8]) -> usize {
+    match s.first() {
+        Some(&b) if b == b'_' || b.is_ascii_alphabetic() => s
+            .iter()
+            .take_while(|&&b| b == b'_' || b.is_ascii_alphanumeric())
+            .count(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_table() {
+        env::set_var("HEALTH_NOTIFY_EXPAND_SET", "value");
+        env::remove_var("HEALTH_NOTIFY_EXPAND_UNSET");
+        let tests: &[(&str, Result<&str, &str>)] = &[
+            ("plain", Ok("plain")),
+            ("", Ok("")),
+            ("$$", Ok("$")),
+            (
+                "$$HEALTH_NOTIFY_EXPAND_SET",
+                Ok("$HEALTH_NOTIFY_EXPAND_SET"),
+            ),
+            ("a$$$$b", Ok("a$$b")),
+            ("$HEALTH_NOTIFY_EXPAND_SET", Ok("value")),
+            ("$HEALTH_NOTIFY_EXPAND_SET/x", Ok("value/x")),
+            ("${HEALTH_NOTIFY_EXPAND_SET}", Ok("value")),
+            ("x${HEALTH_NOTIFY_EXPAND_SET}y", Ok("xvaluey")),
+            ("$HEALTH_NOTIFY_EXPAND_SETy", Ok("")),
+            ("$HEALTH_NOTIFY_EXPAND_UNSET", Ok("")),
+            ("<${HEALTH_NOTIFY_EXPAND_UNSET}>", Ok("<>")),
+            ("$", Ok("$")),
+            ("a$", Ok("a$")),
+            ("$1", Ok("$1")),
+            ("$-x", Ok("$-x")),
+            ("$ x", Ok("$ x")),
+            ("${", Err("unterminated ${")),
+            ("${HEALTH_NOTIFY_EXPAND_SET", Err("unterminated ${")),
+            ("x${HEALTH_NOTIFY_EXPAND_SET}${", Err("unterminated ${")),
+            ("${}", Err("bad variable name \"\"")),
+            ("${1A}", Err("bad variable name \"1A\"")),
+            ("${A-B}", Err("bad variable name \"A-B\"")),
+            ("${A B}", Err("bad variable name \"A B\"")),
+        ];
+        for &(arg, want) in tests {
+            let got = expand(OsStr::new(arg));
+            match want {
+                Ok(want) => assert_eq!(got, Ok(OsString::from(want)), "expand({:?})", arg),
+                Err(want) => {
+                    let err = got.expect_err(arg);
+                    assert!(err.contains(want), "expand({:?}) = {:?}", arg, err);
+                }
+            }
+        }
+    }
+}
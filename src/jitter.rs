@@ -0,0 +1,176 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cell::Cell;
+use std::fmt;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::duration;
+
+/// Maximum random delay added to each wait before a health check,
+/// as given to `--jitter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Jitter {
+    /// A percentage of the wait, like `10%`.
+    Percent(u32),
+    /// A fixed amount, like `200ms`.
+    Duration(Duration),
+}
+
+impl Jitter {
+    /// Parse either a percentage like `10%` or a duration like `200ms`.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let Some(number) = s.strip_suffix('%') else {
+            return duration::parse(s).map(Jitter::Duration);
+        };
+        number
+            .parse::<u32>()
+            .ok()
+            .filter(|&percent| percent <= 100)
+            .map(Jitter::Percent)
+            .ok_or_else(|| format!("{:?}: expected a percentage from 0% to 100%", s))
+    }
+
+    /// Returns `delay` plus a random amount up to the jitter.
+    /// The result is never less than `delay`,
+    /// so checks never run more often than `--interval`.
+    pub(crate) fn add_to(self, delay: Duration) -> Duration {
+        let max = match self {
+            Jitter::Percent(percent) => delay * percent / 100,
+            Jitter::Duration(max) => max,
+        };
+        delay.saturating_add(max.mul_f64(random_fraction()))
+    }
+}
+
+impl fmt::Display for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jitter::Percent(percent) => write!(f, "{}%", percent),
+            Jitter::Duration(max) => write!(f, "{:?}", max),
+        }
+    }
+}
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+/// Seed the generator from the process ID and the time,
+/// so that instances started at the same moment still diverge.
+fn seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    nanos ^ (u64::from(process::id()) << 32)
+}
+
+/// Returns a pseudo-random number in [0, 1) using SplitMix64.
+/// This isn't suitable for anything but spreading out timers.
+fn random_fraction() -> f64 {
+    let x = STATE.with(|state| {
+        let s = state.get().wrapping_add(0x9e3779b97f4a7c15);
+        state.set(s);
+        s
+    });
+    let mut z = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    // The top 53 bits fill an f64's mantissa exactly.
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_percent() {
+        assert_eq!(Jitter::parse("0%"), Ok(Jitter::Percent(0)));
+        assert_eq!(Jitter::parse("10%"), Ok(Jitter::Percent(10)));
+        assert_eq!(Jitter::parse("100%"), Ok(Jitter::Percent(100)));
+        for arg in ["101%", "-5%", "%", "1.5%", "ten%"] {
+            assert!(
+                Jitter::parse(arg).is_err(),
+                "Jitter::parse({:?}) succeeded",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(
+            Jitter::parse("200ms"),
+            Ok(Jitter::Duration(Duration::from_millis(200)))
+        );
+        assert_eq!(
+            Jitter::parse("2"),
+            Ok(Jitter::Duration(Duration::from_secs(2)))
+        );
+        for arg in ["", "soon", "5x"] {
+            assert!(
+                Jitter::parse(arg).is_err(),
+                "Jitter::parse({:?}) succeeded",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn add_to_stays_in_bounds() {
+        let delay = Duration::from_secs(10);
+        for (jitter, max) in [
+            (Jitter::Percent(0), delay),
+            (Jitter::Percent(10), Duration::from_secs(11)),
+            (Jitter::Percent(100), Duration::from_secs(20)),
+            (Jitter::Duration(Duration::ZERO), delay),
+            (
+                Jitter::Duration(Duration::from_millis(500)),
+                Duration::from_millis(10_500),
+            ),
+        ] {
+            for _ in 0..1000 {
+                let result = jitter.add_to(delay);
+                assert!(
+                    result >= delay && result <= max,
+                    "{}: {:?} not in [{:?}, {:?}]",
+                    jitter,
+                    result,
+                    delay,
+                    max
+                );
+            }
+        }
+
+        assert_eq!(Jitter::Percent(50).add_to(Duration::ZERO), Duration::ZERO);
+        assert_eq!(
+            Jitter::Duration(Duration::from_secs(1)).add_to(Duration::MAX),
+            Duration::MAX
+        );
+    }
+
+    #[test]
+    fn random_fraction_is_in_unit_interval() {
+        for _ in 0..1000 {
+            let x = random_fraction();
+            assert!((0.0..1.0).contains(&x), "{}", x);
+        }
+    }
+}
@@ -18,7 +18,7 @@ use std::cell::UnsafeCell;
 use std::fmt;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Mutex,
+    Mutex, MutexGuard, PoisonError,
 };
 
 /// `LazyFailInit<T>` is a lazily initialized synchronized container type
@@ -41,14 +41,26 @@ impl<T> LazyFailInit<T> {
         }
     }
 
+    /// Construct a `LazyFailInit<T>` that is already initialized with `value`.
+    #[inline]
+    pub(crate) fn with_value(value: T) -> Self {
+        LazyFailInit {
+            initialized: AtomicBool::new(true),
+            lock: Mutex::new(()),
+            value: UnsafeCell::new(Some(value)),
+        }
+    }
+
     /// Get a reference to the contained value,
     /// invoking `f` to create it if the `LazyFailInit<T>` is uninitialized.
     /// At most one initialization function may run concurrently,
     /// and once the first function returns `Ok`,
     /// initialization functions will no longer be called.
-    pub(crate) fn get_or_create<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
-        if !self.initialized.load(Ordering::Acquire) {
-            let _lock = self.lock.lock().unwrap();
+    /// If `f` fails, its error is returned as is
+    /// and the `LazyFailInit<T>` stays uninitialized.
+    pub(crate) fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if !self.is_initialized() {
+            let _lock = self.lock();
             if !self.initialized.load(Ordering::Relaxed) {
                 let value = unsafe { &mut *self.value.get() };
                 match f() {
@@ -68,14 +80,47 @@ impl<T> LazyFailInit<T> {
     /// returning `Some(ref)` if the `LazyFailInit<T>` has been initialized
     /// or `None` if it has not.
     pub(crate) fn get(&self) -> Option<&T> {
-        if self.initialized.load(Ordering::Acquire) {
+        if self.is_initialized() {
             unsafe { self.extract() }
         } else {
-            let _lock = self.lock.lock().unwrap();
+            let _lock = self.lock();
             unsafe { self.extract() }
         }
     }
 
+    /// Take the contained value out of the `LazyFailInit<T>`,
+    /// leaving it uninitialized so that the next call to
+    /// [`get_or_try_init`](Self::get_or_try_init) runs its initialization function.
+    /// Holding `&mut self` guarantees that no references to the old value remain.
+    #[allow(dead_code)]
+    pub(crate) fn take(&mut self) -> Option<T> {
+        *self.initialized.get_mut() = false;
+        self.value.get_mut().take()
+    }
+
+    /// Consume the `LazyFailInit<T>`,
+    /// returning the contained value if it has been initialized.
+    #[allow(dead_code)]
+    pub(crate) fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+
+    /// Reports whether the `LazyFailInit<T>` has been initialized.
+    /// This does not wait for an in-progress initialization.
+    #[inline]
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Acquire)
+    }
+
+    /// Acquire the initialization lock.
+    /// The lock only guards against concurrent initialization
+    /// and the value is only written after an initialization function returns successfully,
+    /// so a panic in an initialization function leaves nothing inconsistent:
+    /// poisoning can be ignored.
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     #[inline(always)]
     unsafe fn extract(&self) -> Option<&T> {
         (*self.value.get()).as_ref()
@@ -102,3 +147,70 @@ impl<T> Default for LazyFailInit<T> {
         LazyFailInit::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+
+    #[test]
+    fn panicking_initializer_does_not_poison() {
+        let cell = LazyFailInit::<i32>::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = cell.get_or_try_init(|| -> Result<i32, ()> { panic!("initializer failed") });
+        }));
+        assert!(result.is_err());
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(42)), Ok(&42));
+        assert_eq!(cell.get(), Some(&42));
+    }
+
+    #[test]
+    fn take_allows_reinitialization() {
+        let mut cell = LazyFailInit::new();
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+        assert_eq!(cell.take(), Some(1));
+        assert_eq!(cell.get(), None);
+
+        let mut calls = 0;
+        let value = cell.get_or_try_init(|| {
+            calls += 1;
+            Ok::<_, ()>(2)
+        });
+        assert_eq!(value, Ok(&2));
+        assert_eq!(calls, 1);
+        assert_eq!(cell.take(), Some(2));
+        assert_eq!(cell.take(), None);
+    }
+
+    #[test]
+    fn get_or_try_init_returns_error_and_retries() {
+        let cell = LazyFailInit::<i32>::new();
+        assert_eq!(cell.get_or_try_init(|| Err("refused")), Err("refused"));
+        assert!(!cell.is_initialized());
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, &str>(7)), Ok(&7));
+        assert!(cell.is_initialized());
+        assert_eq!(
+            cell.get_or_try_init(|| -> Result<i32, &str> { panic!("initializer called again") }),
+            Ok(&7)
+        );
+    }
+
+    #[test]
+    fn into_inner() {
+        assert_eq!(LazyFailInit::<i32>::new().into_inner(), None);
+        assert_eq!(LazyFailInit::with_value(3).into_inner(), Some(3));
+
+        let cell = LazyFailInit::new();
+        let _ = cell.get_or_try_init(|| Err::<i32, _>(()));
+        assert_eq!(cell.into_inner(), None);
+
+        let cell = LazyFailInit::new();
+        let _ = cell.get_or_try_init(|| Ok::<_, ()>(5));
+        assert_eq!(cell.into_inner(), Some(5));
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::unistd::Pid;
+
+use crate::log;
+use crate::pidfd::PidFd;
+use crate::stop_sequence::StopSequence;
+
+/// `Lifetime` enforces `--max-lifetime` from a timer thread.
+/// When the time is up, the thread runs the stop sequence on the child program,
+/// and the main thread notices the exit as usual.
+#[derive(Debug)]
+pub(crate) struct Lifetime {
+    expired: Arc<AtomicBool>,
+}
+
+impl Lifetime {
+    /// Start a timer that runs `stop` on `target` after `max`
+    /// unless `child` has been reaped by then.
+    pub(crate) fn start(
+        child: &Child,
+        target: Pid,
+        stop: StopSequence,
+        max: Duration,
+    ) -> io::Result<Self> {
+        let pid = child.id();
+        let pidfd = PidFd::open(child.id())?;
+        let expired = Arc::new(AtomicBool::new(false));
+        let thread_expired = Arc::clone(&expired);
+        thread::Builder::new()
+            .name("max-lifetime".to_string())
+            .spawn(move || {
+                thread::sleep(max);
+                // The child's PID can be reused once it's reaped,
+                // but the pidfd still refers to the original process.
+                if !pidfd.is_unreaped() {
+                    return;
+                }
+                log::info!("max_lifetime"; "reached --max-lifetime of {:?}", max);
+                thread_expired.store(true, Ordering::Release);
+                stop.run(pid, Some(&pidfd), target);
+            })?;
+        Ok(Lifetime { expired })
+    }
+
+    /// Reports whether the child program was terminated for reaching `--max-lifetime`.
+    pub(crate) fn expired(&self) -> bool {
+        self.expired.load(Ordering::Acquire)
+    }
+}
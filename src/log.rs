@@ -0,0 +1,391 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A tiny logger that writes one line per event to standard error.
+//! Only warnings are logged unless `-v` or `--log-format=json` is given on the command line.
+//!
+//! Each event has a short machine-readable name, a set of fields,
+//! and a human-readable message.
+//! The text format only shows the tag and the message;
+//! the JSON format shows everything.
+
+use std::fmt::{self, Write as _};
+use std::io::{self, Write as _};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Importance of a log message.
+/// A message is written if the number of `-v` flags is at least its level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub(crate) enum Level {
+    /// Unexpected conditions that health-notify recovered from.
+    /// These are written even without `-v`
+    /// so that failed notifications and the like don't go unnoticed.
+    Warn = 0,
+    /// Lifecycle events: spawns, check results, notifications, forwarded signals.
+    Info = 1,
+    /// Details useful when debugging health-notify itself.
+    Debug = 2,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Output format for log lines, as selected by `--log-format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[repr(u8)]
+pub(crate) enum Format {
+    /// A timestamp followed by the message.
+    #[default]
+    Text = 0,
+    /// A JSON object with `timestamp`, `level`, `event`, `message`, and event-specific fields.
+    Json = 1,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Text as u8);
+static TAG: OnceLock<String> = OnceLock::new();
+
+/// Set the number of `-v` flags given.
+pub(crate) fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+/// Set the format of log lines.
+pub(crate) fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// Set the tag that identifies this instance in every log line.
+/// An empty tag is omitted.
+/// Only the first call has an effect.
+pub(crate) fn set_tag(tag: String) {
+    let _ = TAG.set(tag);
+}
+
+fn tag() -> Option<&'static str> {
+    TAG.get().map(String::as_str).filter(|tag| !tag.is_empty())
+}
+
+fn format() -> Format {
+    if FORMAT.load(Ordering::Relaxed) == Format::Json as u8 {
+        Format::Json
+    } else {
+        Format::Text
+    }
+}
+
+/// Report whether messages at `level` are written.
+pub(crate) fn enabled(level: Level) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// A field value in a log event.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Value<'a> {
+    Int(i64),
+    Str(&'a str),
+    Null,
+}
+
+impl From<i32> for Value<'_> {
+    fn from(n: i32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<u32> for Value<'_> {
+    fn from(n: u32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<i64> for Value<'_> {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl<'a, T: Into<Value<'a>>> From<Option<T>> for Value<'a> {
+    fn from(x: Option<T>) -> Self {
+        x.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Write an event at `level` if it is enabled.
+/// Use the [`warning`], [`info`], and [`debug`] macros instead of calling this directly.
+pub(crate) fn log(level: Level, event: &str, fields: &[(&str, Value)], message: fmt::Arguments) {
+    if enabled(level) {
+        write_line(Some(level), event, fields, message);
+    }
+}
+
+/// Write an event regardless of verbosity.
+/// In the text format, the message is written without a timestamp.
+pub(crate) fn summary(event: &str, fields: &[(&str, Value)], message: fmt::Arguments) {
+    write_line(None, event, fields, message);
+}
+
+fn write_line(
+    level: Option<Level>,
+    event: &str,
+    fields: &[(&str, Value)],
+    message: fmt::Arguments,
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = Timestamp(now.as_millis());
+    let mut line = String::new();
+    match format() {
+        Format::Text => {
+            match level {
+                Some(_) => {
+                    let _ = write!(line, "{} ", timestamp);
+                }
+                None => line.push_str("health-notify: "),
+            }
+            if let Some(tag) = tag() {
+                let _ = write!(line, "[{}] ", tag);
+            }
+            let _ = write!(line, "{}", message);
+        }
+        Format::Json => {
+            let _ = write!(line, "{{\"timestamp\":\"{}\"", timestamp);
+            if let Some(level) = level {
+                let _ = write!(line, ",\"level\":\"{}\"", level.as_str());
+            }
+            if let Some(tag) = tag() {
+                line.push_str(",\"tag\":");
+                write_json_string(&mut line, tag);
+            }
+            line.push_str(",\"event\":");
+            write_json_string(&mut line, event);
+            write_json_fields(&mut line, fields, message);
+            line.push('}');
+        }
+    }
+    line.push('\n');
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// Format `fields` and `message` as a JSON object,
+/// the same way as a JSON log line without the timestamp, level, tag, and event.
+pub(crate) fn json_object(fields: &[(&str, Value)], message: fmt::Arguments) -> String {
+    let mut out = String::from("{");
+    write_json_fields(&mut out, fields, message);
+    // The message always follows a comma, so drop the leading one.
+    out.remove(1);
+    out.push('}');
+    out
+}
+
+/// Write `,"key":value` for each field, then `,"message":"..."`.
+fn write_json_fields(out: &mut String, fields: &[(&str, Value)], message: fmt::Arguments) {
+    for (key, value) in fields {
+        out.push(',');
+        write_json_string(out, key);
+        out.push(':');
+        match value {
+            Value::Int(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            Value::Str(s) => write_json_string(out, s),
+            Value::Null => out.push_str("null"),
+        }
+    }
+    out.push_str(",\"message\":");
+    write_json_string(out, &message.to_string());
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Log an event at [`Level::Warn`]. See [`info`] for syntax.
+macro_rules! warning {
+    ($event:literal $(, $key:ident = $value:expr)* ; $($arg:tt)*) => {
+        $crate::log::log(
+            $crate::log::Level::Warn,
+            $event,
+            &[$((stringify!($key), $crate::log::Value::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Log an event at [`Level::Info`].
+///
+/// ```ignore
+/// log::info!("check_failed", attempt = 3, exit_code = 1; "check attempt {} failed", 3);
+/// ```
+macro_rules! info {
+    ($event:literal $(, $key:ident = $value:expr)* ; $($arg:tt)*) => {
+        $crate::log::log(
+            $crate::log::Level::Info,
+            $event,
+            &[$((stringify!($key), $crate::log::Value::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Log an event at [`Level::Debug`]. See [`info`] for syntax.
+macro_rules! debug {
+    ($event:literal $(, $key:ident = $value:expr)* ; $($arg:tt)*) => {
+        $crate::log::log(
+            $crate::log::Level::Debug,
+            $event,
+            &[$((stringify!($key), $crate::log::Value::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+pub(crate) use {debug, info, warning};
+
+/// Milliseconds since the Unix epoch, formatted as an RFC 3339 UTC timestamp.
+struct Timestamp(u128);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.0 % 1000;
+        let secs = self.0 / 1000;
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            millis
+        )
+    }
+}
+
+/// Convert a count of days since 1970-01-01 into a (year, month, day) triple.
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_string(s: &str) -> String {
+        let mut out = String::new();
+        write_json_string(&mut out, s);
+        out
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string(""), r#""""#);
+        assert_eq!(json_string("plain text"), r#""plain text""#);
+        assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\dir\"), r#""C:\\dir\\""#);
+        assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(
+            json_string("\0\x01\x1b\x1f"),
+            r#""\u0000\u0001\u001b\u001f""#
+        );
+        // DEL and everything above it is valid unescaped in JSON.
+        assert_eq!(json_string("\x7f"), "\"\x7f\"");
+        assert_eq!(json_string("café ☃ 🦀"), "\"café ☃ 🦀\"");
+    }
+
+    #[test]
+    fn json_object_fields() {
+        assert_eq!(
+            json_object(&[], format_args!("done")),
+            r#"{"message":"done"}"#
+        );
+        assert_eq!(
+            json_object(
+                &[
+                    ("exit_code", Value::Int(-1)),
+                    ("reason", Value::Str("line\n\"quoted\"")),
+                    ("signal", Value::Null),
+                ],
+                format_args!("child {}", "exited"),
+            ),
+            r#"{"exit_code":-1,"reason":"line\n\"quoted\"","signal":null,"message":"child exited"}"#
+        );
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        // 2100 and 1900 are not leap years.
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+        assert_eq!(civil_from_days(-25508), (1900, 3, 1));
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00.000Z");
+        let millis = (19782 * 86400 + 86399) * 1000 + 999;
+        assert_eq!(Timestamp(millis).to_string(), "2024-02-29T23:59:59.999Z");
+    }
+}
@@ -14,36 +14,291 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
-use std::process::{self, Child, Command};
-use std::time::Duration;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, FromRawFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use check_output::CheckOutput;
 use clap::{
+    builder::{
+        BoolishValueParser, NonEmptyStringValueParser, OsStringValueParser, TypedValueParser,
+    },
+    error::ErrorKind,
+    parser::ValueSource,
     value_parser, Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueHint,
 };
-use nix::sys::signal::kill;
-use nix::unistd::Pid;
-use signal_hook::{
-    consts::{SIGCHLD, SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
-    iterator::{exfiltrator::WithOrigin, SignalsInfo},
-};
+use config::Config;
+use jitter::Jitter;
+use lifetime::Lifetime;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc::{c_int, pid_t};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{access, AccessFlags, Group, Pid, Uid, User};
+use notify_proxy::NotifyProxy;
+use pidfd::PidFd;
+use ready_marker::ReadyMarker;
+use regex::Regex;
+use rlimit::{Limit, Resource};
+use sd_notify::SystemdNotify;
+use signal_hook::consts::{FORBIDDEN, SIGCHLD, SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::low_level::siginfo::Origin;
+use signals::Signals;
+use stop_sequence::StopSequence;
 
+mod check_output;
+mod close_fds;
+mod config;
+mod deferred_signals;
+mod duration;
+mod expand_env;
+mod jitter;
 mod lazy_fail_init;
+mod lifetime;
+mod log;
+mod metrics;
+mod notify_proxy;
+mod pidfd;
+mod ready_marker;
+mod result_file;
+mod rlimit;
 mod sd_notify;
+mod signal_name;
+mod signals;
+mod stop_sequence;
+mod user_ns;
 
 #[derive(Clone, Debug)]
 struct Options {
+    config: Option<PathBuf>,
+    verbose: u8,
+    quiet: bool,
+    log_format: log::Format,
+    tag: Option<String>,
+    metrics_file: Option<PathBuf>,
+    result_file: Option<PathBuf>,
     child_notify: bool,
+    proxy_notify: bool,
+    check_after_notify: bool,
+    check_notify: bool,
+    require_notify: bool,
+    ready_payload: Option<String>,
+    process_group: bool,
+    setsid: bool,
+    user: Option<String>,
+    group: Option<String>,
+    check_user: Option<String>,
+    check_group: Option<String>,
+    chdir: Option<PathBuf>,
+    argv0: Option<OsString>,
+    expand_env: bool,
+    umask: Option<Mode>,
+    limits: Vec<(Resource, Limit)>,
+    oom_score_adj: Option<i32>,
+    user_ns: bool,
+    clean_env: bool,
+    close_fds: bool,
+    env: Vec<EnvAssignment>,
+    check_env: Vec<EnvAssignment>,
+    stdout: Option<PathBuf>,
+    stderr: Option<StderrTarget>,
+    reload_on_hup: bool,
+    no_forward_signals: bool,
+    no_expect_foreground: bool,
+    no_wait: bool,
+    forward_signals: Vec<Signal>,
+    defer_signals: Vec<Signal>,
+    barrier: bool,
+    dry_run: bool,
+    interval: Duration,
+    initial_delay: Option<Duration>,
+    check_delay_after_spawn: Option<Duration>,
+    jitter: Option<Jitter>,
+    extend_timeout: Option<Duration>,
+    ready_timeout: Option<Duration>,
+    startup_timeout: Option<Duration>,
+    check_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    ready_regex: Option<Regex>,
+    liveness_interval: Option<Duration>,
+    liveness_failures: u32,
+    kill_signal: Signal,
+    kill_timeout: Duration,
+    stop_sequence: Option<StopSequence>,
+    max_spawn_failures: u32,
+    warmup_failures: u32,
+    check_degraded_exit: Vec<i32>,
+    check_success_exit: Vec<i32>,
+    check_ready_exit: Vec<i32>,
+    check_fail_exit: Vec<i32>,
+    exit_code_on_unhealthy: Option<i32>,
+    exit_code_on_timeout: Option<i32>,
+    fd_store: Vec<FdStoreEntry>,
+    fd_store_remove_on_exit: bool,
+    ready_fd: Option<RawFd>,
+    ready_fd_message: String,
+    also_check: Vec<Vec<OsString>>,
+    pre_check: Option<Vec<OsString>>,
+    on_ready: Option<Vec<OsString>>,
+    on_unhealthy: Option<Vec<OsString>>,
+    check_script: Option<PathBuf>,
+    shell: PathBuf,
     child_argv: Vec<OsString>,
     check_argv: Vec<OsString>,
+    /// Path of the child program, filled in by [`resolve_programs`].
+    child_program: PathBuf,
+    /// Paths of the health check and `--pre-check` programs by name,
+    /// filled in by [`resolve_programs`].
+    check_programs: HashMap<OsString, PathBuf>,
 }
 
-impl CommandFactory for Options {
-    fn command() -> clap::Command {
+impl Options {
+    /// Reports whether startup waits for a signal from the child program
+    /// rather than running health checks.
+    fn waits_for_child(&self) -> bool {
+        self.ready_timeout.is_some() || self.ready_regex.is_some()
+    }
+
+    /// Returns the time to wait between spawning the child program and its first health check.
+    /// `--initial-delay` takes precedence over `--check-delay-after-spawn`.
+    fn first_check_delay(&self) -> Duration {
+        self.initial_delay
+            .or(self.check_delay_after_spawn)
+            .unwrap_or(self.interval)
+    }
+
+    /// Returns the notification to send once the child program is ready.
+    fn ready_payload(&self) -> &str {
+        self.ready_payload.as_deref().unwrap_or("READY=1")
+    }
+
+    /// Returns the signals that stop the child program:
+    /// `--stop-sequence`, or else `--kill-signal` followed by SIGKILL after `--kill-timeout`.
+    fn stop_sequence(&self) -> StopSequence {
+        match &self.stop_sequence {
+            Some(sequence) => sequence.clone(),
+            None => StopSequence::escalating(self.kill_signal, self.kill_timeout),
+        }
+    }
+
+    /// Returns `delay` with any `--jitter` added.
+    fn jittered(&self, delay: Duration) -> Duration {
+        self.jitter.map_or(delay, |jitter| jitter.add_to(delay))
+    }
+
+    /// Reports whether a health check was given in place of the positional check program.
+    fn has_check(&self) -> bool {
+        !self.check_argv.is_empty() || self.check_script.is_some()
+    }
+
+    /// Returns the path to execute for the health check or `--pre-check` program `name`.
+    fn check_program<'a>(&'a self, name: &'a OsStr) -> &'a Path {
+        self.check_programs
+            .get(name)
+            .map_or(Path::new(name), PathBuf::as_path)
+    }
+
+    /// Returns the argument vectors of every health check program,
+    /// starting with the positional one.
+    /// The positional check is optional with `--ready-timeout` or `--ready-regex`.
+    fn checks(&self) -> impl Iterator<Item = &[OsString]> {
+        std::iter::once(self.check_argv.as_slice())
+            .filter(|argv| !argv.is_empty())
+            .chain(self.also_check.iter().map(Vec::as_slice))
+    }
+}
+
+/// The default token that ends the child program's arguments
+/// and those of options like `--also-check`.
+const DEFAULT_SEPARATOR: &str = ";";
+
+impl Options {
+    /// Build the argument parser, using `separator` to end argument vectors.
+    fn command_with_separator(separator: &str) -> clap::Command {
         clap::Command::new("health-notify")
-            .override_usage(
-                "health-notify [options] CHILD_PROGRAM [ARG [...]] \\; CHECK_PROGRAM [ARG [...]]",
+            .version(env!("HEALTH_NOTIFY_VERSION"))
+            .override_usage(format!(
+                "health-notify [options] CHILD_PROGRAM [ARG [...]] {} CHECK_PROGRAM [ARG [...]]",
+                if separator == DEFAULT_SEPARATOR {
+                    // Escaped as it would be typed in a shell.
+                    "\\;"
+                } else {
+                    separator
+                }
+            ))
+            .args_override_self(true)
+            .arg(
+                Arg::new("config")
+                    .help("Read options from a TOML file; command-line arguments take precedence")
+                    .long("config")
+                    .value_name("FILE")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .help("Log lifecycle events to stderr (repeat for more detail)")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count),
+            )
+            .arg(
+                Arg::new("quiet")
+                    .help("Don't print a summary line on exit (also disables --verbose)")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("log_format")
+                    .help("Format of log lines (json implies -v)")
+                    .long("log-format")
+                    .env("HEALTH_NOTIFY_LOG_FORMAT")
+                    .value_name("FORMAT")
+                    .default_value("text")
+                    .value_parser(value_parser!(log::Format)),
+            )
+            .arg(
+                Arg::new("tag")
+                    .help(
+                        "Label at the start of every log line \
+                         [default: the child program's file name]",
+                    )
+                    .long("tag")
+                    .env("HEALTH_NOTIFY_TAG")
+                    .value_name("STRING"),
+            )
+            .arg(
+                Arg::new("metrics_file")
+                    .help(
+                        "Write check counters and readiness to a file \
+                         for the node_exporter textfile collector after each check",
+                    )
+                    .long("metrics-file")
+                    .env("HEALTH_NOTIFY_METRICS_FILE")
+                    .value_name("PATH")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath),
+            )
+            .arg(
+                Arg::new("result_file")
+                    .help("Write a JSON summary of how the run ended to a file on exit")
+                    .long("result-file")
+                    .env("HEALTH_NOTIFY_RESULT_FILE")
+                    .value_name("PATH")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath),
             )
             .arg(
                 Arg::new("child_notify")
@@ -51,13 +306,676 @@ impl CommandFactory for Options {
                     .long("child-notify")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("proxy_notify")
+                    .help(
+                        "Give the child program its own NOTIFY_SOCKET \
+                         and relay its notifications to systemd (implies --child-notify)",
+                    )
+                    .long("proxy-notify")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("check_after_notify")
+                    .help(
+                        "Wait for the child program's first notification \
+                         before running health checks (implies --proxy-notify)",
+                    )
+                    .long("check-after-notify")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("check_notify")
+                    .help("Pass NOTIFY_SOCKET environment variable to health check programs")
+                    .long("check-notify")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("require_notify")
+                    .help("Fail if NOTIFY_SOCKET is not set or READY=1 can't be sent")
+                    .long("require-notify")
+                    .env("HEALTH_NOTIFY_REQUIRE_NOTIFY")
+                    .action(ArgAction::SetTrue)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                Arg::new("ready_payload")
+                    .help(
+                        "Newline-separated assignments to send in place of READY=1 \
+                         once the child program is ready (e.g. READY=1 and STATUS=serving)",
+                    )
+                    .long("ready-payload")
+                    .env("HEALTH_NOTIFY_READY_PAYLOAD")
+                    .value_name("STRING")
+                    .value_parser(NonEmptyStringValueParser::new()),
+            )
+            .arg(
+                Arg::new("process_group")
+                    .help(
+                        "Run child program in a new process group and forward signals to the group",
+                    )
+                    .long("process-group")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("setsid")
+                    .help("Run child program in a new session (implies a new process group)")
+                    .long("setsid")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("user")
+                    .help("Run child and check programs as the given user name or ID and its group")
+                    .long("user")
+                    .value_name("USER"),
+            )
+            .arg(
+                Arg::new("group")
+                    .help("Run child and check programs as the given group name or ID")
+                    .long("group")
+                    .value_name("GROUP"),
+            )
+            .arg(
+                Arg::new("check_user")
+                    .help(
+                        "Run health check programs as the given user name or ID and its group \
+                         instead of --user",
+                    )
+                    .long("check-user")
+                    .value_name("USER"),
+            )
+            .arg(
+                Arg::new("check_group")
+                    .help("Run health check programs as the given group name or ID")
+                    .long("check-group")
+                    .value_name("GROUP"),
+            )
+            .arg(
+                Arg::new("chdir")
+                    .help("Working directory for child and check programs")
+                    .long("chdir")
+                    .value_name("PATH")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::DirPath),
+            )
+            .arg(
+                Arg::new("umask")
+                    .help("File mode creation mask for child and check programs (e.g. 027)")
+                    .long("umask")
+                    .env("HEALTH_NOTIFY_UMASK")
+                    .value_name("OCTAL")
+                    .value_parser(parse_umask),
+            )
+            .arg(
+                Arg::new("limit_core")
+                    .help("Core file size limit for the child program (bytes)")
+                    .long("limit-core")
+                    .value_name("SOFT[:HARD]")
+                    .value_parser(Limit::parse),
+            )
+            .arg(
+                Arg::new("limit_nofile")
+                    .help(
+                        "Open file descriptor limit for the child program \
+                         (a number or \"unlimited\")",
+                    )
+                    .long("limit-nofile")
+                    .value_name("SOFT[:HARD]")
+                    .value_parser(Limit::parse),
+            )
+            .arg(
+                Arg::new("limit_nproc")
+                    .help("Process limit for the child program's user")
+                    .long("limit-nproc")
+                    .value_name("SOFT[:HARD]")
+                    .value_parser(Limit::parse),
+            )
+            .arg(
+                Arg::new("oom_score_adj")
+                    .help(
+                        "OOM killer score adjustment for the child program, \
+                         from -1000 (never kill) to 1000 (kill first)",
+                    )
+                    .long("oom-score-adj")
+                    .value_name("N")
+                    .allow_negative_numbers(true)
+                    .value_parser(value_parser!(i32).range(-1000..=1000)),
+            )
+            .arg(
+                Arg::new("argv0")
+                    .help(
+                        "Name to pass to the child program as argv[0] \
+                         [default: the child program as given]",
+                    )
+                    .long("argv0")
+                    .value_name("NAME")
+                    .value_parser(value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("expand_env")
+                    .help(
+                        "Expand ${VAR} and $VAR from the environment \
+                         in the child and check programs' arguments ($$ is a literal $)",
+                    )
+                    .long("expand-env")
+                    .env("HEALTH_NOTIFY_EXPAND_ENV")
+                    .action(ArgAction::SetTrue)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                Arg::new("user_ns")
+                    .help("Run child and check programs in a new user namespace as root")
+                    .long("health-check-user-ns")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("clean_env")
+                    .help(
+                        "Start the child program with an empty environment besides --env variables",
+                    )
+                    .long("clean-env")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("close_fds")
+                    .help(
+                        "Don't let child, check, or hook programs inherit file descriptors \
+                         besides standard input, output, and error",
+                    )
+                    .long("close-fds")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("env")
+                    .help("Set (KEY=VALUE) or unset (KEY) a variable for the child program only")
+                    .long("env")
+                    .value_name("KEY[=VALUE]")
+                    .action(ArgAction::Append)
+                    .value_parser(OsStringValueParser::new().try_map(EnvAssignment::parse)),
+            )
+            .arg(
+                Arg::new("check_env")
+                    .help("Set (KEY=VALUE) or unset (KEY) a variable for the check program only")
+                    .long("check-env")
+                    .value_name("KEY[=VALUE]")
+                    .action(ArgAction::Append)
+                    .value_parser(OsStringValueParser::new().try_map(EnvAssignment::parse)),
+            )
+            .arg(
+                Arg::new("stdout")
+                    .help("Append child program's standard output to a file")
+                    .long("stdout")
+                    .value_name("PATH")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath),
+            )
+            .arg(
+                Arg::new("stderr")
+                    .help(
+                        "Append child program's standard error to a file (&1 for standard output)",
+                    )
+                    .long("stderr")
+                    .value_name("PATH")
+                    .value_parser(OsStringValueParser::new().map(StderrTarget::parse))
+                    .value_hint(ValueHint::FilePath),
+            )
+            .arg(
+                Arg::new("reload_on_hup")
+                    .help(
+                        "On SIGHUP, send RELOADING=1 and send READY=1 again once a check passes \
+                         (with --proxy-notify, once the child program sends READY=1)",
+                    )
+                    .long("reload-on-hup")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("no_forward_signals")
+                    .help("Don't forward received signals to the child program")
+                    .long("no-forward-signals")
+                    .env("HEALTH_NOTIFY_NO_FORWARD_SIGNALS")
+                    .action(ArgAction::SetTrue)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                Arg::new("no_expect_foreground")
+                    .help(
+                        "Don't treat the child program exiting with code 0 \
+                         right after it starts as a failure to stay in the foreground",
+                    )
+                    .long("no-expect-foreground")
+                    .env("HEALTH_NOTIFY_NO_EXPECT_FOREGROUND")
+                    .action(ArgAction::SetTrue)
+                    .value_parser(BoolishValueParser::new()),
+            )
+            .arg(
+                Arg::new("no_wait")
+                    .help(
+                        "Exit with code 0 once the child program is ready, leaving it running \
+                         (signals are no longer forwarded and its exit code is not reported)",
+                    )
+                    .long("no-wait")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all([
+                        "liveness_interval",
+                        "reload_on_hup",
+                        "max_lifetime",
+                        "ready_regex",
+                        "proxy_notify",
+                        "check_after_notify",
+                        "fd_store_remove_on_exit",
+                    ]),
+            )
+            .arg(
+                Arg::new("forward_signal")
+                    .help(
+                        "Also forward SIGNAL to the child program, \
+                         like SIGTSTP and SIGCONT for job control (may be repeated)",
+                    )
+                    .long("forward-signal")
+                    .value_name("SIGNAL")
+                    .action(ArgAction::Append)
+                    .value_parser(parse_forward_signal),
+            )
+            .arg(
+                Arg::new("defer_signal")
+                    .help(
+                        "Hold SIGNAL until the child program is ready, \
+                         then forward it once (may be repeated)",
+                    )
+                    .long("defer-signal")
+                    .value_name("SIGNAL")
+                    .action(ArgAction::Append)
+                    .value_parser(parse_forward_signal)
+                    .conflicts_with_all(["no_forward_signals", "no_wait"]),
+            )
+            .arg(
+                Arg::new("barrier")
+                    .help("After sending READY=1, wait for systemd to process it")
+                    .long("barrier")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("dry_run")
+                    .help("Validate options and programs, print the plan, and exit without running")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("interval")
+                    .help("Time to wait before each health check (e.g. 250ms, 5s, 2m)")
+                    .long("interval")
+                    .env("HEALTH_NOTIFY_INTERVAL")
+                    .value_name("DURATION")
+                    .default_value("1s")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("initial_delay")
+                    .help(
+                        "Time to wait before the first health check \
+                         [default: --check-delay-after-spawn, then --interval]",
+                    )
+                    .long("initial-delay")
+                    .env("HEALTH_NOTIFY_INITIAL_DELAY")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("check_delay_after_spawn")
+                    .help(
+                        "Time to wait after spawning the child program before checking it \
+                         [default: --interval]; --initial-delay overrides this for the first spawn",
+                    )
+                    .long("check-delay-after-spawn")
+                    .env("HEALTH_NOTIFY_CHECK_DELAY_AFTER_SPAWN")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("jitter")
+                    .help(
+                        "Add a random delay of up to DURATION or PERCENT% \
+                         to each wait before a health check",
+                    )
+                    .long("jitter")
+                    .env("HEALTH_NOTIFY_JITTER")
+                    .value_name("DURATION|PERCENT%")
+                    .value_parser(Jitter::parse),
+            )
+            .arg(
+                Arg::new("extend_timeout")
+                    .help(
+                        "Before each health check, ask systemd to extend its startup timeout \
+                         to the check delay plus DURATION",
+                    )
+                    .long("extend-timeout")
+                    .env("HEALTH_NOTIFY_EXTEND_TIMEOUT")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("ready_timeout")
+                    .help(
+                        "Instead of running health checks during startup, \
+                         wait up to DURATION for the child program to send READY=1 \
+                         (or print a --ready-regex line)",
+                    )
+                    .long("ready-timeout")
+                    .env("HEALTH_NOTIFY_READY_TIMEOUT")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("startup_timeout")
+                    .help(
+                        "Terminate the child program and exit \
+                         if no health check passes within DURATION of starting it",
+                    )
+                    .long("startup-timeout")
+                    .env("HEALTH_NOTIFY_STARTUP_TIMEOUT")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("check_timeout")
+                    .help(
+                        "Kill health check programs that run longer than DURATION \
+                         and count the attempt as failed",
+                    )
+                    .long("check-timeout")
+                    .env("HEALTH_NOTIFY_CHECK_TIMEOUT")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("max_lifetime")
+                    .help(
+                        "Terminate the child program with --kill-signal \
+                         once DURATION has passed since it started, healthy or not, \
+                         and exit with code 124",
+                    )
+                    .long("max-lifetime")
+                    .env("HEALTH_NOTIFY_MAX_LIFETIME")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("ready_regex")
+                    .help(
+                        "Instead of running health checks during startup, \
+                         wait for the child program to print a line matching PATTERN",
+                    )
+                    .long("ready-regex")
+                    .value_name("PATTERN")
+                    .conflicts_with("stdout")
+                    .value_parser(Regex::new),
+            )
+            .arg(
+                Arg::new("liveness_interval")
+                    .help("After the child program is ready, keep running health checks this often")
+                    .long("liveness-interval")
+                    .env("HEALTH_NOTIFY_LIVENESS_INTERVAL")
+                    .value_name("DURATION")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("liveness_failures")
+                    .help(
+                        "Terminate the child program and exit \
+                         after N consecutive failed liveness checks",
+                    )
+                    .long("liveness-failures")
+                    .env("HEALTH_NOTIFY_LIVENESS_FAILURES")
+                    .value_name("N")
+                    .default_value("3")
+                    .value_parser(value_parser!(u32).range(1..)),
+            )
+            .arg(
+                Arg::new("kill_signal")
+                    .help("Signal to send when health-notify terminates a process on its own")
+                    .long("kill-signal")
+                    .env("HEALTH_NOTIFY_KILL_SIGNAL")
+                    .value_name("SIGNAL")
+                    .default_value("SIGTERM")
+                    .value_parser(signal_name::parse),
+            )
+            .arg(
+                Arg::new("kill_timeout")
+                    .help(
+                        "Send SIGKILL if the child program is still running \
+                         DURATION after --kill-signal",
+                    )
+                    .long("kill-timeout")
+                    .env("HEALTH_NOTIFY_KILL_TIMEOUT")
+                    .value_name("DURATION")
+                    .default_value("90s")
+                    .conflicts_with("stop_sequence")
+                    .value_parser(duration::parse),
+            )
+            .arg(
+                Arg::new("stop_sequence")
+                    .help(
+                        "Signals to send in turn when health-notify stops the child program \
+                         on its own, each but the last with a time to wait for it to exit \
+                         (e.g. TERM:10s,INT:5s,KILL) \
+                         [default: --kill-signal:--kill-timeout,KILL]",
+                    )
+                    .long("stop-sequence")
+                    .env("HEALTH_NOTIFY_STOP_SEQUENCE")
+                    .value_name("SIGNAL:DURATION,...,SIGNAL")
+                    .value_parser(StopSequence::parse),
+            )
+            .arg(
+                Arg::new("max_spawn_failures")
+                    .help(
+                        "Give up after N consecutive check program spawn failures \
+                         or deaths by signal (0 for no limit)",
+                    )
+                    .long("max-spawn-failures")
+                    .env("HEALTH_NOTIFY_MAX_SPAWN_FAILURES")
+                    .value_name("N")
+                    .default_value("5")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("warmup_failures")
+                    .help("Log failures of the first N health check attempts only with -vv")
+                    .long("warmup-failures")
+                    .env("HEALTH_NOTIFY_WARMUP_FAILURES")
+                    .value_name("N")
+                    .default_value("0")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("check_degraded_exit")
+                    .help("Health check exit code that means ready but degraded (may be repeated)")
+                    .long("check-degraded-exit")
+                    .value_name("CODE")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(i32)),
+            )
+            .arg(
+                Arg::new("check_success_exit")
+                    .help(
+                        "Health check exit code that means ready, \
+                         replacing the default of 0 (may be repeated)",
+                    )
+                    .long("check-success-exit")
+                    .value_name("CODE")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(i32)),
+            )
+            .arg(
+                Arg::new("check_ready_exit")
+                    .help("Non-zero health check exit code that means ready (may be repeated)")
+                    .long("check-ready-exit")
+                    .value_name("CODE")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(i32)),
+            )
+            .arg(
+                Arg::new("check_fail_exit")
+                    .help(
+                        "Health check exit code that means startup failed permanently: \
+                         terminate the child program and exit (may be repeated)",
+                    )
+                    .long("check-fail-exit")
+                    .value_name("CODE")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(i32)),
+            )
+            .arg(
+                Arg::new("exit_code_on_unhealthy")
+                    .help(
+                        "Exit code to use when health-notify gives up on startup \
+                         for any reason other than the child program exiting",
+                    )
+                    .long("exit-code-on-unhealthy")
+                    .env("HEALTH_NOTIFY_EXIT_CODE_ON_UNHEALTHY")
+                    .value_name("CODE")
+                    .value_parser(value_parser!(i32).range(0..=255)),
+            )
+            .arg(
+                Arg::new("exit_code_on_timeout")
+                    .help(
+                        "Exit code to use when the child program isn't ready \
+                         within --ready-timeout or --startup-timeout \
+                         [default: --exit-code-on-unhealthy or 1]",
+                    )
+                    .long("exit-code-on-timeout")
+                    .env("HEALTH_NOTIFY_EXIT_CODE_ON_TIMEOUT")
+                    .value_name("CODE")
+                    .value_parser(value_parser!(i32).range(0..=255)),
+            )
+            .arg(
+                Arg::new("fd_store")
+                    .help(
+                        "Once ready, hand file descriptor FD to systemd's file descriptor store \
+                         as NAME (may be repeated)",
+                    )
+                    .long("fd-store")
+                    .value_name("NAME=FD")
+                    .action(ArgAction::Append)
+                    .value_parser(FdStoreEntry::parse),
+            )
+            .arg(
+                Arg::new("ready_fd")
+                    .help("Once ready, write --ready-fd-message to file descriptor FD and close it")
+                    .long("ready-fd")
+                    .value_name("FD")
+                    .value_parser(value_parser!(RawFd).range(3..)),
+            )
+            .arg(
+                Arg::new("ready_fd_message")
+                    .help("Text to write to --ready-fd")
+                    .long("ready-fd-message")
+                    .value_name("STRING")
+                    .default_value("\n")
+                    .hide_default_value(true)
+                    .requires("ready_fd"),
+            )
+            .arg(
+                Arg::new("fd_store_remove_on_exit")
+                    .help(
+                        "If the child program exits with code 0, \
+                         remove the --fd-store file descriptors from systemd's store",
+                    )
+                    .long("fd-store-remove-on-exit")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("separator")
+                    .help("Token that ends argument vectors instead of ';'")
+                    .long("separator")
+                    .env("HEALTH_NOTIFY_SEPARATOR")
+                    .value_name("TOKEN")
+                    .allow_hyphen_values(true)
+                    .value_parser(NonEmptyStringValueParser::new()),
+            )
+            .arg(
+                Arg::new("also_check")
+                    .help(format!(
+                        "Another health check program that must pass in the same attempt, \
+                         terminated by '{}' (may be repeated)",
+                        separator
+                    ))
+                    .long("also-check")
+                    .value_name("PROGRAM")
+                    .action(ArgAction::Append)
+                    .num_args(1..)
+                    .value_terminator(separator.to_string())
+                    .allow_hyphen_values(true)
+                    .value_parser(value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("pre_check")
+                    .help(format!(
+                        "Program to run once after starting the child program \
+                         and before the first health check, terminated by '{}'",
+                        separator
+                    ))
+                    .long("pre-check")
+                    .value_name("PROGRAM")
+                    .num_args(1..)
+                    .value_terminator(separator.to_string())
+                    .allow_hyphen_values(true)
+                    .value_parser(value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("on_ready")
+                    .help(format!(
+                        "Program to run once the child program is ready, terminated by '{}'",
+                        separator
+                    ))
+                    .long("on-ready")
+                    .value_name("PROGRAM")
+                    .num_args(1..)
+                    .value_terminator(separator.to_string())
+                    .allow_hyphen_values(true)
+                    .value_parser(value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("on_unhealthy")
+                    .help(format!(
+                        "Program to run if the child program never becomes ready, \
+                         terminated by '{}'",
+                        separator
+                    ))
+                    .long("on-unhealthy")
+                    .value_name("PROGRAM")
+                    .num_args(1..)
+                    .value_terminator(separator.to_string())
+                    .allow_hyphen_values(true)
+                    .value_parser(value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("check_script")
+                    .help(
+                        "Run the health check script in FILE (or - for standard input) \
+                         with --shell instead of a check program",
+                    )
+                    .long("check-script")
+                    .value_name("FILE")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath)
+                    .conflicts_with("check_argv"),
+            )
+            .arg(
+                Arg::new("shell")
+                    .help("Shell that runs --check-script")
+                    .long("shell")
+                    .value_name("PATH")
+                    .default_value("/bin/sh")
+                    .value_parser(value_parser!(PathBuf))
+                    .value_hint(ValueHint::ExecutablePath),
+            )
             .arg(
                 Arg::new("child_argv")
                     .help("Child program to run")
                     .action(ArgAction::Set)
                     .num_args(1..)
-                    .value_terminator(";")
-                    .required(true)
+                    .value_terminator(separator.to_string())
+                    .required_unless_present("config")
                     .allow_hyphen_values(true)
                     .value_parser(value_parser!(OsString)),
             )
@@ -66,13 +984,18 @@ impl CommandFactory for Options {
                     .help("Health checking program to run during startup")
                     .action(ArgAction::Set)
                     .num_args(1..)
-                    .required(true)
                     .allow_hyphen_values(true)
                     .trailing_var_arg(true)
                     .value_parser(value_parser!(OsString))
                     .value_hint(ValueHint::CommandWithArguments),
             )
     }
+}
+
+impl CommandFactory for Options {
+    fn command() -> clap::Command {
+        Self::command_with_separator(DEFAULT_SEPARATOR)
+    }
 
     fn command_for_update() -> clap::Command {
         Self::command()
@@ -87,11 +1010,106 @@ impl FromArgMatches for Options {
 
     fn from_arg_matches_mut(matches: &mut ArgMatches) -> Result<Self, clap::Error> {
         let mut opts = Options {
+            config: None,
+            verbose: 0,
+            quiet: false,
+            log_format: log::Format::Text,
+            tag: None,
+            metrics_file: None,
+            result_file: None,
             child_notify: false,
+            proxy_notify: false,
+            check_after_notify: false,
+            check_notify: false,
+            require_notify: false,
+            ready_payload: None,
+            process_group: false,
+            setsid: false,
+            user: None,
+            group: None,
+            check_user: None,
+            check_group: None,
+            chdir: None,
+            argv0: None,
+            expand_env: false,
+            umask: None,
+            limits: Vec::new(),
+            oom_score_adj: None,
+            user_ns: false,
+            clean_env: false,
+            close_fds: false,
+            env: Vec::new(),
+            check_env: Vec::new(),
+            stdout: None,
+            stderr: None,
+            reload_on_hup: false,
+            no_forward_signals: false,
+            no_expect_foreground: false,
+            no_wait: false,
+            forward_signals: Vec::new(),
+            defer_signals: Vec::new(),
+            barrier: false,
+            dry_run: false,
+            interval: Duration::from_secs(1),
+            initial_delay: None,
+            check_delay_after_spawn: None,
+            jitter: None,
+            extend_timeout: None,
+            ready_timeout: None,
+            startup_timeout: None,
+            check_timeout: None,
+            max_lifetime: None,
+            ready_regex: None,
+            liveness_interval: None,
+            liveness_failures: 3,
+            kill_signal: Signal::SIGTERM,
+            kill_timeout: Duration::from_secs(90),
+            stop_sequence: None,
+            max_spawn_failures: 5,
+            warmup_failures: 0,
+            check_degraded_exit: Vec::new(),
+            check_success_exit: vec![0],
+            check_ready_exit: Vec::new(),
+            check_fail_exit: Vec::new(),
+            exit_code_on_unhealthy: None,
+            exit_code_on_timeout: None,
+            fd_store: Vec::new(),
+            fd_store_remove_on_exit: false,
+            ready_fd: None,
+            ready_fd_message: "\n".to_string(),
+            also_check: Vec::new(),
+            pre_check: None,
+            on_ready: None,
+            on_unhealthy: None,
+            check_script: None,
+            shell: PathBuf::from("/bin/sh"),
             child_argv: Vec::new(),
             check_argv: Vec::new(),
+            child_program: PathBuf::new(),
+            check_programs: HashMap::new(),
         };
         opts.update_from_arg_matches_mut(matches)?;
+        // clap can't tell a forgotten separator from a missing check program,
+        // so check_argv is validated here instead of being marked required.
+        if opts.ready_timeout.is_some()
+            && opts.ready_regex.is_none()
+            && !opts.child_notify
+            && !opts.proxy_notify
+        {
+            return Err(clap::Error::raw(
+                ErrorKind::MissingRequiredArgument,
+                "--ready-timeout requires --child-notify or --ready-regex",
+            ));
+        }
+        if opts.config.is_none() && !opts.has_check() && !opts.waits_for_child() {
+            return Err(clap::Error::raw(
+                ErrorKind::MissingRequiredArgument,
+                "no health check program given\n\n\
+                 The child program's arguments must be followed by `;` and the check program:\n\n  \
+                 health-notify sleep 60 \\; true\n\n\
+                 Most shells need the `;` to be escaped as `\\;` or quoted as `';'`.",
+            ));
+        }
         Ok(opts)
     }
 
@@ -101,158 +1119,3475 @@ impl FromArgMatches for Options {
     }
 
     fn update_from_arg_matches_mut(&mut self, matches: &mut ArgMatches) -> Result<(), clap::Error> {
+        self.config = matches.remove_one::<PathBuf>("config");
+        self.verbose = matches.get_count("verbose");
+        self.quiet = matches.get_flag("quiet");
+        if let Some(format) = matches.remove_one::<log::Format>("log_format") {
+            self.log_format = format;
+        }
+        self.tag = matches.remove_one::<String>("tag");
+        self.metrics_file = matches.remove_one::<PathBuf>("metrics_file");
+        self.result_file = matches.remove_one::<PathBuf>("result_file");
         self.child_notify = matches.get_flag("child_notify");
+        self.check_after_notify = matches.get_flag("check_after_notify");
+        self.proxy_notify = matches.get_flag("proxy_notify") || self.check_after_notify;
+        self.check_notify = matches.get_flag("check_notify");
+        self.require_notify = matches.get_flag("require_notify");
+        self.ready_payload = matches.remove_one::<String>("ready_payload");
+        self.process_group = matches.get_flag("process_group");
+        self.setsid = matches.get_flag("setsid");
+        self.user = matches.remove_one::<String>("user");
+        self.group = matches.remove_one::<String>("group");
+        self.check_user = matches.remove_one::<String>("check_user");
+        self.check_group = matches.remove_one::<String>("check_group");
+        self.chdir = matches.remove_one::<PathBuf>("chdir");
+        self.argv0 = matches.remove_one::<OsString>("argv0");
+        self.expand_env = matches.get_flag("expand_env");
+        self.umask = matches.remove_one::<Mode>("umask");
+        self.limits = [
+            ("limit_core", Resource::Core),
+            ("limit_nofile", Resource::Nofile),
+            ("limit_nproc", Resource::Nproc),
+        ]
+        .into_iter()
+        .filter_map(|(id, resource)| Some((resource, matches.remove_one::<Limit>(id)?)))
+        .collect();
+        self.oom_score_adj = matches.remove_one::<i32>("oom_score_adj");
+        self.user_ns = matches.get_flag("user_ns");
+        self.clean_env = matches.get_flag("clean_env");
+        self.close_fds = matches.get_flag("close_fds");
+        self.env = matches
+            .remove_many::<EnvAssignment>("env")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.check_env = matches
+            .remove_many::<EnvAssignment>("check_env")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.stdout = matches.remove_one::<PathBuf>("stdout");
+        self.stderr = matches.remove_one::<StderrTarget>("stderr");
+        self.reload_on_hup = matches.get_flag("reload_on_hup");
+        self.no_forward_signals = matches.get_flag("no_forward_signals");
+        self.no_expect_foreground = matches.get_flag("no_expect_foreground");
+        self.no_wait = matches.get_flag("no_wait");
+        self.forward_signals = matches
+            .remove_many::<Signal>("forward_signal")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.defer_signals = matches
+            .remove_many::<Signal>("defer_signal")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.barrier = matches.get_flag("barrier");
+        self.dry_run = matches.get_flag("dry_run");
+        if let Some(interval) = matches.remove_one::<Duration>("interval") {
+            self.interval = interval;
+        }
+        self.initial_delay = matches.remove_one::<Duration>("initial_delay");
+        self.check_delay_after_spawn = matches.remove_one::<Duration>("check_delay_after_spawn");
+        self.jitter = matches.remove_one::<Jitter>("jitter");
+        self.extend_timeout = matches.remove_one::<Duration>("extend_timeout");
+        self.ready_timeout = matches.remove_one::<Duration>("ready_timeout");
+        self.startup_timeout = matches.remove_one::<Duration>("startup_timeout");
+        self.check_timeout = matches.remove_one::<Duration>("check_timeout");
+        self.max_lifetime = matches.remove_one::<Duration>("max_lifetime");
+        self.ready_regex = matches.remove_one::<Regex>("ready_regex");
+        self.liveness_interval = matches.remove_one::<Duration>("liveness_interval");
+        if let Some(n) = matches.remove_one::<u32>("liveness_failures") {
+            self.liveness_failures = n;
+        }
+        if let Some(sig) = matches.remove_one::<Signal>("kill_signal") {
+            self.kill_signal = sig;
+        }
+        if let Some(timeout) = matches.remove_one::<Duration>("kill_timeout") {
+            self.kill_timeout = timeout;
+        }
+        self.stop_sequence = matches.remove_one::<StopSequence>("stop_sequence");
+        if let Some(n) = matches.remove_one::<u32>("max_spawn_failures") {
+            self.max_spawn_failures = n;
+        }
+        if let Some(n) = matches.remove_one::<u32>("warmup_failures") {
+            self.warmup_failures = n;
+        }
+        self.check_degraded_exit = matches
+            .remove_many::<i32>("check_degraded_exit")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        if let Some(codes) = matches.remove_many::<i32>("check_success_exit") {
+            self.check_success_exit = codes.collect();
+        }
+        self.check_ready_exit = matches
+            .remove_many::<i32>("check_ready_exit")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.check_fail_exit = matches
+            .remove_many::<i32>("check_fail_exit")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.exit_code_on_unhealthy = matches.remove_one::<i32>("exit_code_on_unhealthy");
+        self.exit_code_on_timeout = matches.remove_one::<i32>("exit_code_on_timeout");
+        self.fd_store = matches
+            .remove_many::<FdStoreEntry>("fd_store")
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        self.fd_store_remove_on_exit = matches.get_flag("fd_store_remove_on_exit");
+        self.ready_fd = matches.remove_one::<RawFd>("ready_fd");
+        if let Some(message) = matches.remove_one::<String>("ready_fd_message") {
+            self.ready_fd_message = message;
+        }
+        self.also_check = matches
+            .remove_occurrences::<OsString>("also_check")
+            .map(|occurrences| occurrences.map(Iterator::collect).collect())
+            .unwrap_or_default();
+        self.pre_check = matches
+            .remove_many::<OsString>("pre_check")
+            .map(Iterator::collect);
+        self.on_ready = matches
+            .remove_many::<OsString>("on_ready")
+            .map(Iterator::collect);
+        self.on_unhealthy = matches
+            .remove_many::<OsString>("on_unhealthy")
+            .map(Iterator::collect);
+        self.check_script = matches.remove_one::<PathBuf>("check_script");
+        if let Some(shell) = matches.remove_one::<PathBuf>("shell") {
+            self.shell = shell;
+        }
         self.child_argv = matches
             .remove_many::<OsString>("child_argv")
-            .expect("child_argv is required")
-            .collect();
+            .map(Iterator::collect)
+            .unwrap_or_default();
         self.check_argv = matches
             .remove_many::<OsString>("check_argv")
-            .expect("check_argv is required")
-            .collect();
+            .map(Iterator::collect)
+            .unwrap_or_default();
         Ok(())
     }
 }
 
 impl Parser for Options {}
 
-fn main() -> Result<()> {
-    let options = Options::parse();
-
-    let mut signals =
-        SignalsInfo::<WithOrigin>::new(&[SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGHUP, SIGCHLD])?;
-    let notify = if options.child_notify {
-        sd_notify::SystemdNotify::from_env()
-    } else {
-        unsafe { sd_notify::SystemdNotify::take_from_env() }
-    };
+/// An `--env` or `--check-env` argument.
+#[derive(Clone, Debug)]
+enum EnvAssignment {
+    /// `KEY=VALUE`: set `KEY` to `VALUE`.
+    Set(OsString, OsString),
+    /// `KEY`: remove `KEY` from the environment.
+    Remove(OsString),
+}
 
-    let mut child = Command::new(&options.child_argv[0])
-        .args(&options.child_argv[1..])
-        .spawn()?;
+impl EnvAssignment {
+    /// Parse a `KEY=VALUE` or `KEY` argument.
+    /// Only the first `=` separates the key from the value.
+    fn parse(arg: OsString) -> Result<Self, String> {
+        let bytes = arg.as_bytes();
+        match bytes.iter().position(|&b| b == b'=') {
+            Some(0) => Err(format!("{:?}: empty variable name", arg)),
+            Some(i) => Ok(EnvAssignment::Set(
+                OsStr::from_bytes(&bytes[..i]).to_owned(),
+                OsStr::from_bytes(&bytes[i + 1..]).to_owned(),
+            )),
+            None if bytes.is_empty() => Err("empty variable name".to_string()),
+            None => Ok(EnvAssignment::Remove(arg)),
+        }
+    }
 
-    if let Err(exit_code) = wait_for_startup(&mut child, &options.check_argv, &mut signals) {
-        process::exit(exit_code);
+    fn apply(&self, command: &mut Command) {
+        match self {
+            EnvAssignment::Set(key, value) => command.env(key, value),
+            EnvAssignment::Remove(key) => command.env_remove(key),
+        };
     }
-    if let Some(notify) = notify {
-        let _ = notify.notify("READY=1");
+
+    /// Returns the `PATH` that a program started with `assignments` is searched for in:
+    /// the last value given for `PATH`, if any, or else our own.
+    fn search_path(assignments: &[EnvAssignment]) -> Option<OsString> {
+        assignments
+            .iter()
+            .rev()
+            .find_map(|assignment| match assignment {
+                EnvAssignment::Set(key, value) if key == "PATH" => Some(Some(value.clone())),
+                EnvAssignment::Remove(key) if key == "PATH" => Some(None),
+                _ => None,
+            })
+            .unwrap_or_else(|| env::var_os("PATH"))
     }
-    process::exit(propagate_signals(&mut child, &mut signals));
 }
 
-fn wait_for_startup<A: AsRef<OsStr>>(
-    child: &mut Child,
-    check_argv: &[A],
-    signals: &mut SignalsInfo<WithOrigin>,
-) -> Result<(), i32> {
-    // Wait for some period of time then start a check subprocess.
-    // We may get interrupted by signals or the check subprocess may fail to start,
-    // so this can loop.
-    'waitLoop: loop {
-        let mut sleep_time = Duration::from_secs(1);
-        let mut check_child = loop {
-            match shuteye::sleep(sleep_time) {
-                None => {
-                    let spawn_result = Command::new(check_argv[0].as_ref())
-                        .args(check_argv.iter().skip(1).map(AsRef::as_ref))
-                        .env_remove(sd_notify::ENV_VAR)
-                        .spawn();
-                    if let Ok(check_child) = spawn_result {
-                        break check_child;
-                    } else {
-                        continue 'waitLoop;
-                    }
-                }
-                Some(remaining) => {
-                    for sig in signals.pending() {
-                        match sig.signal {
-                            SIGCHLD => {
-                                if sig.process.and_then(|p| u32::try_from(p.pid).ok())
-                                    == Some(child.id())
-                                {
-                                    let exit_code = child
-                                        .wait()
-                                        .ok()
-                                        .and_then(|status| status.code())
-                                        .unwrap_or(1);
-                                    return Err(exit_code);
-                                }
-                            }
-                            _ => {
-                                let _ = kill(
-                                    Pid::from_raw(child.id().try_into().unwrap()),
-                                    nix::sys::signal::Signal::try_from(sig.signal).unwrap(),
-                                );
-                            }
-                        }
-                    }
+/// An `--fd-store` argument.
+#[derive(Clone, Debug)]
+struct FdStoreEntry {
+    name: String,
+    fd: RawFd,
+}
 
-                    sleep_time = remaining;
-                }
-            }
-        };
+impl FdStoreEntry {
+    /// Parse a `NAME=FD` argument.
+    /// Names follow systemd's rules for `FDNAME=`:
+    /// at most 255 printable ASCII characters other than `:`.
+    fn parse(arg: &str) -> Result<Self, String> {
+        let (name, fd) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("{:?}: expected NAME=FD", arg))?;
+        if name.is_empty()
+            || name.len() > 255
+            || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+        {
+            return Err(format!("{:?}: invalid file descriptor name", name));
+        }
+        let fd = fd
+            .parse::<RawFd>()
+            .ok()
+            .filter(|&fd| fd >= 0)
+            .ok_or_else(|| format!("{:?}: invalid file descriptor", fd))?;
+        Ok(FdStoreEntry {
+            name: name.to_string(),
+            fd,
+        })
+    }
+}
 
-        // Now we're waiting for either process to exit.
-        'checkLoop: loop {
-            for sig in signals.wait() {
-                match sig.signal {
-                    SIGCHLD => {
-                        if let Some(sig_pid) = sig.process.and_then(|p| u32::try_from(p.pid).ok()) {
-                            if sig_pid == child.id() {
-                                let exit_code = child
-                                    .wait()
-                                    .ok()
-                                    .and_then(|status| status.code())
-                                    .unwrap_or(1);
-                                let _ = kill(
-                                    Pid::from_raw(check_child.id().try_into().unwrap()),
-                                    nix::sys::signal::Signal::SIGTERM,
-                                );
-                                let _ = check_child.wait();
-                                return Err(exit_code);
-                            } else if sig_pid == check_child.id() {
-                                let success =
-                                    check_child.wait().is_ok_and(|status| status.success());
-                                if success {
-                                    return Ok(());
-                                } else {
-                                    break 'checkLoop;
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        let _ = kill(
-                            Pid::from_raw(child.id().try_into().unwrap()),
-                            nix::sys::signal::Signal::try_from(sig.signal).unwrap(),
-                        );
-                    }
-                }
-            }
+/// Verify that the `--fd-store` descriptors are open
+/// and fit within the manager's `FDSTORE_MAX`.
+fn check_fd_store(entries: &[FdStoreEntry]) -> Result<()> {
+    if let Some(max) = sd_notify::fd_store_max() {
+        if entries.len() > max {
+            bail!(
+                "--fd-store given {} file descriptors, but FDSTORE_MAX={}",
+                entries.len(),
+                max
+            );
         }
     }
+    for entry in entries {
+        fcntl(entry.fd, FcntlArg::F_GETFD)
+            .with_context(|| format!("--fd-store {}={}", entry.name, entry.fd))?;
+    }
+    Ok(())
 }
 
-fn propagate_signals(child: &mut Child, signals: &mut SignalsInfo<WithOrigin>) -> i32 {
+/// Take ownership of the `--ready-fd` descriptor,
+/// marking it close-on-exec so that child and check programs don't hold it open.
+fn take_ready_fd(fd: RawFd) -> Result<File> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .with_context(|| format!("--ready-fd {}", fd))?;
+    // SAFETY: The descriptor is open, and --ready-fd hands it to health-notify.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Write `message` to the `--ready-fd` and close it, logging any failure.
+fn write_ready_fd(mut file: File, message: &str) {
+    match file.write_all(message.as_bytes()) {
+        Ok(()) => log::debug!("ready_fd"; "wrote readiness to --ready-fd"),
+        Err(e) => log::warning!(
+            "ready_fd",
+            error = e.to_string().as_str();
+            "writing to --ready-fd: {}",
+            e
+        ),
+    }
+}
+
+/// Parse a `--forward-signal` or `--defer-signal` argument,
+/// rejecting signals that can't be caught or that health-notify uses itself.
+fn parse_forward_signal(s: &str) -> Result<Signal, String> {
+    let signal = signal_name::parse(s)?;
+    if signal == Signal::SIGCHLD || FORBIDDEN.contains(&(signal as c_int)) {
+        return Err(format!("{} can't be forwarded", signal));
+    }
+    Ok(signal)
+}
+
+/// Parse a `--umask` argument as an octal file mode.
+fn parse_umask(s: &str) -> Result<Mode, String> {
+    let bits = u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|&bits| bits <= 0o777)
+        .ok_or_else(|| format!("{:?} is not an octal mode between 000 and 777", s))?;
+    Ok(Mode::from_bits_truncate(bits as _))
+}
+
+/// Configure `command` to set its file mode creation mask before exec.
+fn apply_umask(command: &mut Command, mask: Mode) {
+    // SAFETY: umask(2) is async-signal-safe and can't fail.
+    unsafe {
+        command.pre_exec(move || {
+            umask(mask);
+            Ok(())
+        });
+    }
+}
+
+/// Set the child program's OOM killer score adjustment, logging any failure.
+/// Lowering the adjustment requires privilege,
+/// so a failure doesn't stop the child program.
+fn set_oom_score_adj(child: &Child, adj: i32) {
+    let path = format!("/proc/{}/oom_score_adj", child.id());
+    match fs::write(&path, adj.to_string()) {
+        Ok(()) => log::debug!(
+            "oom_score_adj",
+            pid = child.id(),
+            adj = adj;
+            "set child OOM score adjustment to {}",
+            adj
+        ),
+        Err(e) => log::warning!(
+            "oom_score_adj_error",
+            pid = child.id(),
+            adj = adj,
+            error = e.to_string().as_str();
+            "setting child OOM score adjustment to {}: {}",
+            adj,
+            e
+        ),
+    }
+}
+
+/// Destination of the child program's standard error.
+#[derive(Clone, Debug)]
+enum StderrTarget {
+    File(PathBuf),
+    /// `&1`: the same destination as the child program's standard output.
+    Stdout,
+}
+
+impl StderrTarget {
+    fn parse(arg: OsString) -> Self {
+        if arg == "&1" {
+            StderrTarget::Stdout
+        } else {
+            StderrTarget::File(arg.into())
+        }
+    }
+}
+
+/// Redirect the child program's output as requested by `--stdout` and `--stderr`.
+/// Unset streams are inherited.
+fn redirect_output(command: &mut Command, options: &Options) -> Result<()> {
+    let stdout = options.stdout.as_deref().map(open_output).transpose()?;
+    match &options.stderr {
+        None => {}
+        Some(StderrTarget::File(path)) => {
+            command.stderr(open_output(path)?);
+        }
+        Some(StderrTarget::Stdout) => {
+            let stderr = match &stdout {
+                Some(f) => Stdio::from(f.try_clone()?),
+                None => Stdio::from(io::stdout().as_fd().try_clone_to_owned()?),
+            };
+            command.stderr(stderr);
+        }
+    }
+    if let Some(f) = stdout {
+        command.stdout(f);
+    }
+    Ok(())
+}
+
+fn open_output(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))
+}
+
+/// Replace the file at `path` with `contents` atomically:
+/// the contents are written to a temporary file beside it and renamed into place,
+/// so readers see either the old file or the new one, never a partial write.
+/// The temporary name ends in `.tmp`, so collectors watching for `*.prom` ignore it.
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", process::id()));
+    let result = fs::write(&tmp, contents).and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// User and group IDs that subprocesses switch to before executing.
+#[derive(Clone, Debug, Default)]
+struct Credentials {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Credentials for health check programs
+    /// when they differ from the child program's
+    /// (`--check-user`/`--check-group`).
+    checks: Option<Box<Credentials>>,
+}
+
+impl Credentials {
+    /// Look up the `--user` and `--group` options.
+    /// Each may be a name or a numeric ID.
+    fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Self> {
+        let mut creds = Credentials::default();
+        if let Some(user) = user {
+            let entry = match user.parse::<u32>() {
+                Ok(uid) => {
+                    creds.uid = Some(uid);
+                    User::from_uid(Uid::from_raw(uid))
+                }
+                Err(_) => User::from_name(user),
+            }
+            .with_context(|| format!("look up user {}", user))?;
+            match entry {
+                Some(entry) => {
+                    creds.uid = Some(entry.uid.as_raw());
+                    creds.gid = Some(entry.gid.as_raw());
+                }
+                None if creds.uid.is_some() => {}
+                None => return Err(anyhow!("no such user {}", user)),
+            }
+        }
+        if let Some(group) = group {
+            let gid = match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => Group::from_name(group)
+                    .with_context(|| format!("look up group {}", group))?
+                    .ok_or_else(|| anyhow!("no such group {}", group))?
+                    .gid
+                    .as_raw(),
+            };
+            creds.gid = Some(gid);
+        }
+        Ok(creds)
+    }
+
+    /// Look up all the user and group options.
+    /// A `--check-group` without `--check-user` applies to the `--user`,
+    /// and a `--check-user` without `--check-group` uses that user's group.
+    fn from_options(options: &Options) -> Result<Self> {
+        let mut creds = Credentials::resolve(options.user.as_deref(), options.group.as_deref())?;
+        if options.check_user.is_some() || options.check_group.is_some() {
+            let user = options.check_user.as_deref().or(options.user.as_deref());
+            let checks = Credentials::resolve(user, options.check_group.as_deref())
+                .context("--check-user/--check-group")?;
+            creds.checks = Some(Box::new(checks));
+        }
+        Ok(creds)
+    }
+
+    /// Returns the credentials to run health check programs with.
+    fn for_checks(&self) -> &Credentials {
+        self.checks.as_deref().unwrap_or(self)
+    }
+
+    /// Configure `command` to switch to these credentials.
+    /// When switching users as root, the standard library also drops
+    /// supplementary groups before calling setuid(2).
+    fn apply(&self, command: &mut Command) {
+        if let Some(gid) = self.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = self.uid {
+            command.uid(uid);
+        }
+    }
+}
+
+/// Verify that `dir` is a directory that we can change into.
+fn check_chdir(dir: &Path) -> Result<()> {
+    let metadata = fs::metadata(dir).with_context(|| format!("--chdir {}", dir.display()))?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("--chdir {}: not a directory", dir.display()));
+    }
+    access(dir, AccessFlags::X_OK).with_context(|| format!("--chdir {}", dir.display()))?;
+    Ok(())
+}
+
+/// Find the executable file that `program` refers to,
+/// searching `search_path` (a `PATH` value) if it doesn't contain a slash.
+/// Relative paths are resolved against `dir` (the `--chdir` directory), if given.
+/// The result is absolute, so it can be executed from any directory.
+fn resolve_program(
+    program: &OsStr,
+    dir: Option<&Path>,
+    search_path: Option<&OsStr>,
+) -> Result<PathBuf> {
+    let path = if program.as_bytes().contains(&b'/') {
+        let path = match dir {
+            Some(dir) => dir.join(program),
+            None => PathBuf::from(program),
+        };
+        let metadata = fs::metadata(&path).with_context(|| path.display().to_string())?;
+        if !metadata.is_file() {
+            bail!("{}: not a regular file", path.display());
+        }
+        access(&path, AccessFlags::X_OK).with_context(|| path.display().to_string())?;
+        path
+    } else {
+        search_path
+            .iter()
+            .flat_map(env::split_paths)
+            .map(|dir| dir.join(program))
+            .find(|path| {
+                fs::metadata(path).is_ok_and(|metadata| metadata.is_file())
+                    && access(path, AccessFlags::X_OK).is_ok()
+            })
+            .ok_or_else(|| anyhow!("{}: not found in PATH", program.to_string_lossy()))?
+    };
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    Ok(env::current_dir()
+        .context("get working directory")?
+        .join(path))
+}
+
+/// Find the child, health check, and `--pre-check` programs up front,
+/// so that a missing program is reported before anything is started.
+/// The child program is searched for in the `PATH` it gets from `--env`,
+/// and the others in the `PATH` they get from `--check-env`.
+fn resolve_programs(options: &mut Options) -> Result<()> {
+    let dir = options.chdir.as_deref();
+    let child_path = EnvAssignment::search_path(&options.env);
+    options.child_program = resolve_program(&options.child_argv[0], dir, child_path.as_deref())?;
+    log::debug!(
+        "program_resolved",
+        program = options.child_program.to_string_lossy().as_ref();
+        "child program: {}",
+        options.child_program.display()
+    );
+    let check_path = EnvAssignment::search_path(&options.check_env);
+    let mut check_programs = HashMap::new();
+    for argv in options.checks().chain(options.pre_check.as_deref()) {
+        if check_programs.contains_key(&argv[0]) {
+            continue;
+        }
+        let path = resolve_program(&argv[0], dir, check_path.as_deref())?;
+        log::debug!(
+            "program_resolved",
+            program = path.to_string_lossy().as_ref();
+            "check program {}: {}",
+            argv[0].to_string_lossy(),
+            path.display()
+        );
+        check_programs.insert(argv[0].clone(), path);
+    }
+    options.check_programs = check_programs;
+    Ok(())
+}
+
+/// Print the resolved configuration for `--dry-run`.
+fn print_plan(options: &Options, credentials: &Credentials, notify: Option<&SystemdNotify>) {
+    let argv = |program: &Path, args: &[OsString]| {
+        let mut s = format!("{:?}", program);
+        for arg in args {
+            s.push_str(&format!(" {:?}", arg));
+        }
+        s
+    };
+    println!(
+        "child: {}",
+        argv(&options.child_program, &options.child_argv[1..])
+    );
+    if let Some(argv0) = &options.argv0 {
+        println!("child argv0: {:?}", argv0);
+    }
+    for check_argv in options.checks() {
+        let program = options.check_program(&check_argv[0]);
+        println!("check: {}", argv(program, &check_argv[1..]));
+    }
+    if let Some(uid) = credentials.uid {
+        println!("uid: {}", uid);
+    }
+    if let Some(gid) = credentials.gid {
+        println!("gid: {}", gid);
+    }
+    if let Some(checks) = &credentials.checks {
+        if let Some(uid) = checks.uid {
+            println!("check uid: {}", uid);
+        }
+        if let Some(gid) = checks.gid {
+            println!("check gid: {}", gid);
+        }
+    }
+    if let Some(dir) = &options.chdir {
+        println!("chdir: {}", dir.display());
+    }
+    if let Some(mask) = options.umask {
+        println!("umask: {:03o}", mask.bits());
+    }
+    for (resource, limit) in &options.limits {
+        println!(
+            "{}: {}",
+            resource.option_name().trim_start_matches('-'),
+            limit
+        );
+    }
+    if let Some(adj) = options.oom_score_adj {
+        println!("oom score adj: {}", adj);
+    }
+    println!("initial delay: {:?}", options.first_check_delay());
+    println!("interval: {:?}", options.interval);
+    if let Some(jitter) = options.jitter {
+        println!("jitter: {}", jitter);
+    }
+    if let Some(timeout) = options.ready_timeout {
+        println!("ready timeout: {:?}", timeout);
+    }
+    if let Some(timeout) = options.startup_timeout {
+        println!("startup timeout: {:?}", timeout);
+    }
+    if let Some(timeout) = options.check_timeout {
+        println!("check timeout: {:?}", timeout);
+    }
+    if let Some(max) = options.max_lifetime {
+        println!("max lifetime: {:?}", max);
+    }
+    if let Some(pattern) = &options.ready_regex {
+        println!("ready regex: {}", pattern);
+    }
+    println!("kill signal: {}", options.kill_signal);
+    println!("stop sequence: {}", options.stop_sequence());
+    match notify {
+        Some(notify) => match notify.socket_path() {
+            Some(path) => println!("notify socket: {}", path.display()),
+            None => println!("notify socket: inherited"),
+        },
+        None => println!("notify socket: none"),
+    }
+}
+
+/// Parse the command line, merging in the `--config` file if one was given.
+/// Command-line arguments take precedence over the config file.
+fn parse_options() -> Result<Options> {
+    parse_args(&env::args_os().collect::<Vec<_>>())
+}
+
+/// Parse `args`, including the program name, and the `--config` file they name.
+fn parse_args(args: &[OsString]) -> Result<Options> {
+    let mut command = Options::command_with_separator(&find_separator(args));
+    let mut matches = get_matches(&mut command, args);
+    // Options given on the command line replace the config file's values
+    // rather than adding to them.
+    let given = matches
+        .ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+        .map(|id| id.to_string())
+        .collect::<HashSet<_>>();
+    let options = options_from_matches(&mut command, &mut matches);
+    let Some(path) = &options.config else {
+        return Ok(options);
+    };
+    let config = Config::read(path, &command)?;
+    // Config options go first so that later command-line arguments override them.
+    let mut options = parse_with(
+        command,
+        args.iter()
+            .take(1)
+            .chain(config.args(|id| given.contains(id)))
+            .chain(&args[1..]),
+    );
+    if options.child_argv.is_empty() {
+        options.child_argv = config.child;
+    }
+    if !options.has_check() {
+        options.check_argv = config.check;
+    }
+    if options.child_argv.is_empty() || (!options.has_check() && !options.waits_for_child()) {
+        bail!(
+            "{}: child and check programs must be given in the config file or on the command line",
+            path.display()
+        );
+    }
+    Ok(options)
+}
+
+/// Find the `--separator` token with a first pass over the command line,
+/// since it changes how the rest of the command line is parsed.
+/// The first pass uses the default separator, so it ignores errors
+/// like missing positional arguments, which the real parse will report.
+/// It also leaves `--help` and `--version` to the real parse,
+/// so that the help text shows the chosen separator.
+fn find_separator(args: &[OsString]) -> String {
+    let first_pass = Options::command()
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_version_flag(true);
+    let Ok(mut matches) = first_pass.try_get_matches_from(args) else {
+        return DEFAULT_SEPARATOR.to_string();
+    };
+    matches
+        .remove_one::<String>("separator")
+        .unwrap_or_else(|| DEFAULT_SEPARATOR.to_string())
+}
+
+/// Parse `args` with `command`, exiting with a usage error if they're invalid.
+fn parse_with<I, T>(mut command: clap::Command, args: I) -> Options
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut matches = get_matches(&mut command, args);
+    options_from_matches(&mut command, &mut matches)
+}
+
+/// Match `args` against `command`, exiting with a usage error if they're invalid.
+fn get_matches<I, T>(command: &mut clap::Command, args: I) -> ArgMatches
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    command
+        .try_get_matches_from_mut(args)
+        .unwrap_or_else(|e| e.exit())
+}
+
+/// Convert `matches` to [`Options`], exiting with a usage error if they're invalid.
+fn options_from_matches(command: &mut clap::Command, matches: &mut ArgMatches) -> Options {
+    Options::from_arg_matches_mut(matches).unwrap_or_else(|e| e.format(command).exit())
+}
+
+/// Read the `--check-script` file, if any,
+/// and make it the positional check program run by `--shell`.
+fn load_check_script(options: &mut Options) -> Result<()> {
+    let Some(path) = &options.check_script else {
+        return Ok(());
+    };
+    let script = if path.as_os_str() == "-" {
+        let mut script = Vec::new();
+        io::stdin()
+            .read_to_end(&mut script)
+            .context("--check-script: read standard input")?;
+        script
+    } else {
+        fs::read(path).with_context(|| format!("--check-script {}", path.display()))?
+    };
+    if script.contains(&0) {
+        bail!("--check-script {}: contains a NUL byte", path.display());
+    }
+    // The argument after the script becomes $0, which shows up in the shell's error messages.
+    options.check_argv = vec![
+        options.shell.clone().into(),
+        "-c".into(),
+        OsString::from_vec(script),
+        "health-check".into(),
+    ];
+    Ok(())
+}
+
+/// Apply `--expand-env` to the child and check programs' arguments.
+/// This runs before `--check-script` is loaded, so the script is left for the shell.
+fn expand_argv(options: &mut Options) -> Result<()> {
+    if !options.expand_env {
+        return Ok(());
+    }
+    let check_argvs = options
+        .also_check
+        .iter_mut()
+        .chain(&mut options.pre_check)
+        .map(Vec::as_mut_slice);
+    for argv in [&mut options.child_argv[..], &mut options.check_argv[..]]
+        .into_iter()
+        .chain(check_argvs)
+    {
+        expand_env::expand_all(argv).map_err(|e| anyhow!("--expand-env: {}", e))?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let result = run();
+    if let Err(e) = &result {
+        // Exits that have a summary of their own don't return from run.
+        result_file::write(
+            &[
+                ("outcome", "error".into()),
+                ("exit_code", 1.into()),
+                ("attempts", log::Value::Null),
+                ("startup_duration_ms", log::Value::Null),
+            ],
+            format_args!("{:#}", e),
+        );
+    }
+    result
+}
+
+fn run() -> Result<()> {
+    let mut options = parse_options()?;
+    if let Some(path) = &options.result_file {
+        result_file::set_path(path.clone());
+    }
+    expand_argv(&mut options)?;
+    load_check_script(&mut options)?;
+    log::set_format(options.log_format);
+    log::set_tag(options.tag.clone().unwrap_or_else(|| {
+        Path::new(&options.child_argv[0])
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }));
+    log::set_verbosity(match (options.quiet, options.log_format) {
+        (true, _) => 0,
+        (false, log::Format::Json) => options.verbose.max(1),
+        (false, log::Format::Text) => options.verbose,
+    });
+    let credentials = Credentials::from_options(&options)?;
+    if let Some(dir) = &options.chdir {
+        check_chdir(dir)?;
+    }
+    resolve_programs(&mut options)?;
+    check_fd_store(&options.fd_store)?;
+    if !options
+        .ready_payload()
+        .lines()
+        .any(|line| line == "READY=1")
+    {
+        log::warning!(
+            "ready_payload";
+            "--ready-payload has no READY=1 line, so systemd won't see the service as started"
+        );
+    }
+    let ready_fd = options.ready_fd.map(take_ready_fd).transpose()?;
+    let child_root = credentials
+        .uid
+        .map_or(Uid::effective().is_root(), |uid| uid == 0);
+    for &(resource, limit) in &options.limits {
+        rlimit::check(resource, limit, child_root).map_err(anyhow::Error::msg)?;
+    }
+
+    let mut signals = Signals::new(
+        [SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGHUP, SIGCHLD]
+            .into_iter()
+            .chain(
+                options
+                    .forward_signals
+                    .iter()
+                    .chain(&options.defer_signals)
+                    .map(|&signal| signal as c_int),
+            ),
+    )?;
+    let notify = if options.child_notify {
+        sd_notify::SystemdNotify::from_env()
+    } else {
+        unsafe { sd_notify::SystemdNotify::take_from_env() }
+    };
+    if notify.is_none() {
+        if options.require_notify {
+            bail!(
+                "--require-notify given, but {} is not set",
+                sd_notify::ENV_VAR
+            );
+        }
+        log::debug!("notify_absent"; "{} not set; not sending notifications", sd_notify::ENV_VAR);
+    }
+    if options.check_notify {
+        // NOTIFY_SOCKET has been removed from our own environment,
+        // so pass it explicitly, ahead of any --check-env that overrides it.
+        // A socket inherited through LISTEN_FDS may not have a path to pass.
+        match notify.as_ref().and_then(|notify| notify.socket_path()) {
+            Some(path) => options.check_env.insert(
+                0,
+                EnvAssignment::Set(sd_notify::ENV_VAR.into(), path.into()),
+            ),
+            None => log::debug!(
+                "check_notify_absent";
+                "no {} path to pass to health checks",
+                sd_notify::ENV_VAR
+            ),
+        }
+    }
+    if options.dry_run {
+        print_plan(&options, &credentials, notify.as_ref());
+        return Ok(());
+    }
+
+    if let Some(path) = &options.metrics_file {
+        metrics::set_path(path.clone());
+    }
+
+    // With --proxy-notify or --ready-timeout, the child program talks to a proxy
+    // so that we can see its notifications.
+    let wait_for_notify = options.ready_timeout.is_some() && options.ready_regex.is_none();
+    // An inherited socket with no path can't be named in NOTIFY_SOCKET,
+    // and passing its descriptor wouldn't survive --close-fds,
+    // so --child-notify relays through the proxy instead.
+    let inherited_notify = options.child_notify
+        && notify
+            .as_ref()
+            .is_some_and(|notify| notify.socket_path().is_none());
+    let proxy = if options.proxy_notify || wait_for_notify || inherited_notify {
+        let upstream = notify.as_ref().map(SystemdNotify::try_clone).transpose()?;
+        let (proxy, wakeup) = NotifyProxy::start(upstream).context("start notify proxy")?;
+        if wait_for_notify || options.check_after_notify {
+            signals.add_wakeup(wakeup)?;
+        }
+        Some(proxy)
+    } else {
+        None
+    };
+
+    let mut child_command = Command::new(&options.child_program);
+    child_command
+        .arg0(options.argv0.as_ref().unwrap_or(&options.child_argv[0]))
+        .args(&options.child_argv[1..]);
+    if options.setsid {
+        // setsid(2) fails for process group leaders,
+        // so don't ask for a separate setpgid(2) as well.
+        // The new session's process group ID is the child's PID,
+        // which is what signal_target uses for --process-group.
+        unsafe {
+            child_command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                Ok(())
+            });
+        }
+    } else if options.process_group {
+        child_command.process_group(0);
+    }
+    credentials.apply(&mut child_command);
+    if let Some(mask) = options.umask {
+        apply_umask(&mut child_command, mask);
+    }
+    if !options.limits.is_empty() {
+        rlimit::apply_on_exec(&mut child_command, &options.limits);
+    }
+    if options.close_fds {
+        close_fds::close_on_exec(&mut child_command);
+    }
+    if let Some(dir) = &options.chdir {
+        child_command.current_dir(dir);
+    }
+    if options.user_ns {
+        user_ns::enter_on_exec(&mut child_command);
+    }
+    if options.clean_env {
+        child_command.env_clear();
+    }
+    if let Some(proxy) = &proxy {
+        child_command.env(sd_notify::ENV_VAR, proxy.address());
+    } else if options.child_notify {
+        if let Some(path) = notify.as_ref().and_then(|notify| notify.socket_path()) {
+            // We've removed NOTIFY_SOCKET from our own environment,
+            // so pass it explicitly.
+            child_command.env(sd_notify::ENV_VAR, path);
+        }
+    }
+    for assignment in &options.env {
+        assignment.apply(&mut child_command);
+    }
+    redirect_output(&mut child_command, &options)?;
+    if options.ready_regex.is_some() {
+        child_command.stdout(Stdio::piped());
+    }
+    let mut child = child_command
+        .spawn()
+        .with_context(|| format!("start {}", options.child_argv[0].to_string_lossy()))?;
+    log::info!("child_spawn", pid = child.id(); "spawned child pid={}", child.id());
+    if let Some(proxy) = &proxy {
+        proxy.set_child(child.id());
+    }
+    if let Some(adj) = options.oom_score_adj {
+        set_oom_score_adj(&child, adj);
+    }
+    let lifetime = match (options.max_lifetime, signal_target(&child, &options)) {
+        (Some(max), Some(target)) => Some(
+            Lifetime::start(&child, target, options.stop_sequence(), max)
+                .context("start --max-lifetime timer")?,
+        ),
+        _ => None,
+    };
+    let lifetime_expired = || lifetime.as_ref().is_some_and(Lifetime::expired);
+    let ready_marker = match (&options.ready_regex, child.stdout.take()) {
+        (Some(pattern), Some(stdout)) => {
+            let (marker, wakeup) = ReadyMarker::start(stdout, pattern.clone())
+                .context("watch child program's output")?;
+            signals.add_wakeup(wakeup)?;
+            Some(marker)
+        }
+        _ => None,
+    };
+
+    let start = Instant::now();
+    let mut attempts = 0;
+    let pre_check_result = match &options.pre_check {
+        Some(argv) => run_pre_check(&mut child, &options, &credentials, &mut signals, argv),
+        None => Ok(()),
+    };
+    let startup_result = pre_check_result.and_then(|()| match (&ready_marker, &proxy) {
+        (Some(marker), _) => {
+            attempts = 1;
+            wait_for_child_ready(
+                &mut child,
+                &options,
+                || marker.found(),
+                notify.as_ref(),
+                &mut signals,
+            )
+        }
+        (None, Some(proxy)) if wait_for_notify => {
+            attempts = 1;
+            wait_for_child_ready(
+                &mut child,
+                &options,
+                || proxy.child_ready(),
+                notify.as_ref(),
+                &mut signals,
+            )
+        }
+        _ => match proxy.as_ref().filter(|_| options.check_after_notify) {
+            Some(proxy) => wait_for_first_notify(&mut child, &options, proxy, &mut signals),
+            None => Ok(()),
+        }
+        .and_then(|()| {
+            wait_for_startup(
+                &mut child,
+                &options,
+                &credentials,
+                notify.as_ref(),
+                &mut signals,
+                start,
+                &mut attempts,
+            )
+        }),
+    });
+    let startup_result = match startup_result {
+        Err(StartupError::ChildExited(_)) if lifetime_expired() => Err(
+            StartupError::LifetimeExpired(options.max_lifetime.unwrap_or_default()),
+        ),
+        Err(StartupError::ChildExited(0)) if start.elapsed() < QUICK_EXIT => {
+            let elapsed = start.elapsed();
+            if options.no_expect_foreground {
+                log::warning!(
+                    "child_quick_exit",
+                    elapsed_ms = duration_millis(elapsed);
+                    "child program exited with code 0 after {:.1}s; \
+                     it may have forked into the background",
+                    elapsed.as_secs_f64()
+                );
+                Err(StartupError::ChildExited(0))
+            } else {
+                Err(StartupError::ChildDaemonized(elapsed))
+            }
+        }
+        result => result,
+    };
+    let readiness = match startup_result {
+        Ok(readiness) => readiness,
+        Err(err) => {
+            // The child program has exited or been terminated by now.
+            if let Some(marker) = &ready_marker {
+                marker.drain();
+            }
+            let exit_code = startup_exit_code(&options, &err);
+            if let Some(notify) = &notify {
+                send_startup_failure(notify, &err);
+            }
+            if let Some(argv) = &options.on_unhealthy {
+                // We're about to exit, so wait for the hook to finish.
+                if let Some(hook) = spawn_hook(&options, &credentials, "on-unhealthy", argv) {
+                    wait_hook("on-unhealthy", hook);
+                }
+            }
+            let startup_duration = start.elapsed();
+            report_exit(
+                &options,
+                &[
+                    ("outcome", err.outcome().into()),
+                    ("exit_code", exit_code.into()),
+                    ("attempts", attempts.into()),
+                    (
+                        "startup_duration_ms",
+                        duration_millis(startup_duration).into(),
+                    ),
+                ],
+                format_args!(
+                    "not ready after {}: {}",
+                    DisplayAttempts(attempts, startup_duration),
+                    err
+                ),
+            );
+            process::exit(exit_code);
+        }
+    };
+    let startup_duration = start.elapsed();
+    if let Some(notify) = &notify {
+        if let Err(e) = send_ready(notify, options.ready_payload(), readiness) {
+            if options.require_notify {
+                terminate_child(&mut child, &options);
+                report_exit(
+                    &options,
+                    &[
+                        ("outcome", "notify_failed".into()),
+                        ("exit_code", 1.into()),
+                        ("attempts", attempts.into()),
+                        (
+                            "startup_duration_ms",
+                            duration_millis(startup_duration).into(),
+                        ),
+                    ],
+                    format_args!(
+                        "ready after {}, but sending READY=1 failed: {}",
+                        DisplayAttempts(attempts, startup_duration),
+                        e
+                    ),
+                );
+                process::exit(1);
+            }
+        }
+        if let Some(proxy) = &proxy {
+            proxy.set_started();
+        }
+        store_fds(notify, &options.fd_store);
+        if options.barrier {
+            match notify.barrier(BARRIER_TIMEOUT) {
+                Ok(()) => log::debug!("barrier"; "systemd processed notifications"),
+                Err(e) => log::warning!(
+                    "barrier",
+                    error = e.to_string().as_str();
+                    "waiting for notification barrier: {}",
+                    e
+                ),
+            }
+        }
+    }
+    metrics::set_ready(true);
+    if let Some(file) = ready_fd {
+        write_ready_fd(file, &options.ready_fd_message);
+    }
+    if let Some(argv) = &options.on_ready {
+        // Don't hold up signal forwarding while the hook runs.
+        if let Some(hook) = spawn_hook(&options, &credentials, "on-ready", argv) {
+            thread::spawn(move || wait_hook("on-ready", hook));
+        }
+    }
+    if options.no_wait {
+        // The child program isn't reaped, so it's inherited by our parent
+        // (or the nearest subreaper) once we exit.
+        report_exit(
+            &options,
+            &[
+                ("outcome", "detached".into()),
+                ("exit_code", 0.into()),
+                ("pid", child.id().into()),
+                ("attempts", attempts.into()),
+                (
+                    "startup_duration_ms",
+                    duration_millis(startup_duration).into(),
+                ),
+            ],
+            format_args!(
+                "ready after {}; leaving child pid={} running",
+                DisplayAttempts(attempts, startup_duration),
+                child.id()
+            ),
+        );
+        process::exit(0);
+    }
+    let stopped = propagate_signals(
+        &mut child,
+        &options,
+        &credentials,
+        notify.as_ref(),
+        proxy.as_ref().filter(|_| options.proxy_notify),
+        &mut signals,
+        attempts,
+    );
+    if let Some(marker) = &ready_marker {
+        marker.drain();
+    }
+    metrics::set_ready(false);
+    let exit_code = match stopped {
+        Stopped::ChildExited(_) if lifetime_expired() => {
+            report_exit(
+                &options,
+                &[
+                    ("outcome", "max_lifetime".into()),
+                    ("exit_code", LIFETIME_EXIT_CODE.into()),
+                    ("attempts", attempts.into()),
+                    (
+                        "startup_duration_ms",
+                        duration_millis(startup_duration).into(),
+                    ),
+                ],
+                format_args!(
+                    "ready after {}; then reached --max-lifetime of {:?}",
+                    DisplayAttempts(attempts, startup_duration),
+                    options.max_lifetime.unwrap_or_default()
+                ),
+            );
+            process::exit(LIFETIME_EXIT_CODE);
+        }
+        Stopped::ChildExited(exit_code) => exit_code,
+        Stopped::Unhealthy => {
+            if let Some(argv) = &options.on_unhealthy {
+                if let Some(hook) = spawn_hook(&options, &credentials, "on-unhealthy", argv) {
+                    wait_hook("on-unhealthy", hook);
+                }
+            }
+            let exit_code = options.exit_code_on_unhealthy.unwrap_or(1);
+            report_exit(
+                &options,
+                &[
+                    ("outcome", "unhealthy".into()),
+                    ("exit_code", exit_code.into()),
+                    ("attempts", attempts.into()),
+                    (
+                        "startup_duration_ms",
+                        duration_millis(startup_duration).into(),
+                    ),
+                ],
+                format_args!(
+                    "ready after {}; then failed {} liveness checks in a row",
+                    DisplayAttempts(attempts, startup_duration),
+                    options.liveness_failures
+                ),
+            );
+            process::exit(exit_code);
+        }
+    };
+    if let Some(notify) = notify.as_ref().filter(|notify| !notify.has_sent()) {
+        log::warning!(
+            "notify_never_sent",
+            failures = notify.failures();
+            "no notification reached {} (failed sends: {})",
+            sd_notify::ENV_VAR,
+            notify.failures()
+        );
+    }
+    if exit_code == 0 && options.fd_store_remove_on_exit {
+        if let Some(notify) = &notify {
+            remove_stored_fds(notify, &options.fd_store);
+        }
+    }
+    report_exit(
+        &options,
+        &[
+            ("outcome", "ready".into()),
+            ("exit_code", exit_code.into()),
+            ("attempts", attempts.into()),
+            (
+                "startup_duration_ms",
+                duration_millis(startup_duration).into(),
+            ),
+        ],
+        format_args!(
+            "ready after {}; child exited with code {}",
+            DisplayAttempts(attempts, startup_duration),
+            exit_code
+        ),
+    );
+    process::exit(exit_code);
+}
+
+/// Report how health-notify is exiting:
+/// in the `--result-file`, if any, and in the log unless `--quiet` was given.
+fn report_exit(options: &Options, fields: &[(&str, log::Value)], message: fmt::Arguments) {
+    result_file::write(fields, message);
+    if !options.quiet {
+        log::summary("exit", fields, message);
+    }
+}
+
+fn duration_millis(d: Duration) -> i64 {
+    d.as_millis().try_into().unwrap_or(i64::MAX)
+}
+
+/// Formats the startup statistics for the exit summary,
+/// like `4 attempts / 12.0s`.
+struct DisplayAttempts(u32, Duration);
+
+impl fmt::Display for DisplayAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let DisplayAttempts(attempts, duration) = *self;
+        let noun = if attempts == 1 { "attempt" } else { "attempts" };
+        write!(f, "{} {} / {:.1}s", attempts, noun, duration.as_secs_f64())
+    }
+}
+
+/// Outcome of a successful [`wait_for_startup`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Readiness {
+    /// The health check passed.
+    Ready,
+    /// The health check exited with one of the `--check-degraded-exit` codes:
+    /// the service is usable, but operators should be told something is wrong.
+    Degraded { exit_code: i32 },
+}
+
+/// A child program that exits cleanly this soon after starting
+/// has probably forked into the background.
+const QUICK_EXIT: Duration = Duration::from_secs(1);
+
+/// Exit code for reaching `--max-lifetime`, the same as timeout(1) uses.
+const LIFETIME_EXIT_CODE: i32 = 124;
+
+/// Reason that [`wait_for_startup`] gave up.
+#[derive(Debug)]
+enum StartupError {
+    /// The child program exited with the given code.
+    ChildExited(i32),
+    /// The child program exited with code 0 within [`QUICK_EXIT`] of starting,
+    /// which usually means it forked into the background.
+    ChildDaemonized(Duration),
+    /// The child program was terminated after running for `--max-lifetime`.
+    LifetimeExpired(Duration),
+    /// The check program failed to start `--max-spawn-failures` times in a row.
+    /// The child program has been terminated.
+    CheckSpawn(io::Error),
+    /// The check program exited with one of the `--check-fail-exit` codes.
+    /// The child program has been terminated.
+    CheckAborted(i32),
+    /// The check program was killed by a signal `--max-spawn-failures` times in a row.
+    /// The child program has been terminated.
+    CheckKilled(ExitStatus),
+    /// The `--pre-check` program failed to start.
+    /// The child program has been terminated.
+    PreCheckSpawn(io::Error),
+    /// The `--pre-check` program exited unsuccessfully.
+    /// The child program has been terminated.
+    PreCheckFailed(ExitStatus),
+    /// The child program didn't send `READY=1` or print a `--ready-regex` line
+    /// within `--ready-timeout`.
+    /// The child program has been terminated.
+    ReadyTimeout(Duration),
+    /// No health check passed within `--startup-timeout`.
+    /// The child program has been terminated.
+    StartupTimeout(Duration),
+}
+
+impl StartupError {
+    /// Returns the code that health-notify should exit with.
+    /// Check spawn failures use the shell's codes for commands
+    /// that can't be found (127) or can't be executed (126).
+    fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::ChildExited(code) => *code,
+            StartupError::ChildDaemonized(_) => 1,
+            StartupError::LifetimeExpired(_) => LIFETIME_EXIT_CODE,
+            StartupError::CheckSpawn(e) if e.kind() == io::ErrorKind::NotFound => 127,
+            StartupError::CheckSpawn(_) => 126,
+            StartupError::CheckAborted(_) => 1,
+            StartupError::CheckKilled(status) => shell_exit_code(*status),
+            StartupError::PreCheckSpawn(e) if e.kind() == io::ErrorKind::NotFound => 127,
+            StartupError::PreCheckSpawn(_) => 126,
+            StartupError::PreCheckFailed(status) => shell_exit_code(*status),
+            StartupError::ReadyTimeout(_) => 1,
+            StartupError::StartupTimeout(_) => 1,
+        }
+    }
+
+    /// Reports whether startup was given up because a timeout elapsed.
+    fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            StartupError::ReadyTimeout(_) | StartupError::StartupTimeout(_)
+        )
+    }
+
+    /// Returns the `errno` value that describes the failure, if there is one.
+    fn errno(&self) -> Option<i32> {
+        match self {
+            StartupError::CheckSpawn(e) | StartupError::PreCheckSpawn(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns the `outcome` field for the exit event.
+    fn outcome(&self) -> &'static str {
+        match self {
+            StartupError::ChildExited(_) => "child_exit",
+            StartupError::ChildDaemonized(_) => "child_daemonized",
+            StartupError::LifetimeExpired(_) => "max_lifetime",
+            StartupError::CheckSpawn(_) => "check_spawn_failed",
+            StartupError::CheckAborted(_) => "check_aborted",
+            StartupError::CheckKilled(_) => "check_killed",
+            StartupError::PreCheckSpawn(_) => "pre_check_spawn_failed",
+            StartupError::PreCheckFailed(_) => "pre_check_failed",
+            StartupError::ReadyTimeout(_) => "ready_timeout",
+            StartupError::StartupTimeout(_) => "startup_timeout",
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::ChildExited(code) => write!(f, "child exited with code {}", code),
+            StartupError::ChildDaemonized(elapsed) => write!(
+                f,
+                "child exited with code 0 after {:.1}s, so it may have forked into the background \
+                 (keep it in the foreground or pass --no-expect-foreground)",
+                elapsed.as_secs_f64()
+            ),
+            StartupError::LifetimeExpired(max) => write!(f, "reached --max-lifetime of {:?}", max),
+            StartupError::CheckSpawn(e) => write!(f, "could not start check program: {}", e),
+            StartupError::CheckAborted(code) => {
+                write!(
+                    f,
+                    "check program reported permanent failure (exit={})",
+                    code
+                )
+            }
+            StartupError::CheckKilled(status) => {
+                write!(f, "check program killed ({})", DisplayStatus(Some(*status)))
+            }
+            StartupError::PreCheckSpawn(e) => write!(f, "could not start --pre-check: {}", e),
+            StartupError::PreCheckFailed(status) => {
+                write!(f, "--pre-check failed ({})", DisplayStatus(Some(*status)))
+            }
+            StartupError::ReadyTimeout(timeout) => {
+                write!(f, "child program wasn't ready within {:?}", timeout)
+            }
+            StartupError::StartupTimeout(timeout) => {
+                write!(f, "no health check passed within {:?}", timeout)
+            }
+        }
+    }
+}
+
+/// Returns the code to exit with after giving up on startup.
+/// The child program's own exit code and the `--max-lifetime` code are passed through;
+/// timeouts use `--exit-code-on-timeout` if given,
+/// and every other failure uses `--exit-code-on-unhealthy`.
+fn startup_exit_code(options: &Options, err: &StartupError) -> i32 {
+    match err {
+        StartupError::ChildExited(_) | StartupError::LifetimeExpired(_) => err.exit_code(),
+        _ if err.is_timeout() => options
+            .exit_code_on_timeout
+            .or(options.exit_code_on_unhealthy)
+            .unwrap_or_else(|| err.exit_code()),
+        _ => options
+            .exit_code_on_unhealthy
+            .unwrap_or_else(|| err.exit_code()),
+    }
+}
+
+/// Run health checks until one passes.
+/// `--startup-timeout` is counted from `start`.
+fn wait_for_startup(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    notify: Option<&SystemdNotify>,
+    signals: &mut Signals,
+    start: Instant,
+    attempts: &mut u32,
+) -> Result<Readiness, StartupError> {
+    let deadline = options.startup_timeout.map(|timeout| start + timeout);
+    // Consecutive checks that couldn't run to completion.
+    let mut check_errors = 0u32;
+    loop {
+        if let (Some(timeout), Some(deadline)) = (options.startup_timeout, deadline) {
+            if Instant::now() >= deadline {
+                terminate_child(child, options);
+                return Err(StartupError::StartupTimeout(timeout));
+            }
+        }
+        *attempts += 1;
+        let delay = options.jittered(match *attempts {
+            1 => options.first_check_delay(),
+            _ => options.interval,
+        });
+        if let (Some(notify), Some(extension)) = (notify, options.extend_timeout) {
+            extend_timeout(notify, delay + extension);
+        }
+        match run_check(
+            child,
+            options,
+            credentials,
+            signals,
+            delay,
+            deadline,
+            *attempts,
+        )
+        .map_err(StartupError::ChildExited)?
+        {
+            CheckResult::Passed(readiness) => return Ok(readiness),
+            CheckResult::Failed => check_errors = 0,
+            // Keep waiting: the child program usually exits soon after,
+            // and a passed deadline is noticed at the top of the loop.
+            CheckResult::Interrupted => {}
+            CheckResult::Aborted(code) => {
+                terminate_child(child, options);
+                return Err(StartupError::CheckAborted(code));
+            }
+            CheckResult::SpawnFailed(e) => {
+                check_errors += 1;
+                if check_errors == options.max_spawn_failures {
+                    terminate_child(child, options);
+                    return Err(StartupError::CheckSpawn(e));
+                }
+            }
+            CheckResult::Killed(status) => {
+                check_errors += 1;
+                if check_errors == options.max_spawn_failures {
+                    terminate_child(child, options);
+                    return Err(StartupError::CheckKilled(status));
+                }
+            }
+        }
+    }
+}
+
+/// Wait for the child program to signal readiness itself,
+/// either by sending `READY=1` through the notify proxy
+/// or by printing a `--ready-regex` line,
+/// for at most `--ready-timeout`.
+/// `is_ready` is checked whenever a wait on `signals` ends.
+fn wait_for_child_ready(
+    child: &mut Child,
+    options: &Options,
+    is_ready: impl Fn() -> bool,
+    notify: Option<&SystemdNotify>,
+    signals: &mut Signals,
+) -> Result<Readiness, StartupError> {
+    if let (Some(notify), Some(timeout), Some(extension)) =
+        (notify, options.ready_timeout, options.extend_timeout)
+    {
+        extend_timeout(notify, timeout + extension);
+    }
+    let deadline = options
+        .ready_timeout
+        .map(|timeout| Instant::now() + timeout);
+    loop {
+        if is_ready() {
+            return Ok(Readiness::Ready);
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if let (Some(timeout), Some(Duration::ZERO)) = (options.ready_timeout, remaining) {
+            terminate_child(child, options);
+            return Err(StartupError::ReadyTimeout(timeout));
+        }
+        for sig in signals.wait_timeout(remaining) {
+            match sig.signal {
+                SIGCHLD => {
+                    if has_exited(child) {
+                        return Err(StartupError::ChildExited(reap_child(child)));
+                    }
+                }
+                _ => forward_signal(child, options, &sig),
+            }
+        }
+    }
+}
+
+/// Wait for the child program to send its first notification through the notify proxy,
+/// for `--check-after-notify`.
+fn wait_for_first_notify(
+    child: &mut Child,
+    options: &Options,
+    proxy: &NotifyProxy,
+    signals: &mut Signals,
+) -> Result<(), StartupError> {
+    log::debug!("wait_first_notify"; "waiting for the child program's first notification");
+    loop {
+        if proxy.first_message() {
+            return Ok(());
+        }
+        for sig in signals.wait() {
+            match sig.signal {
+                SIGCHLD => {
+                    if has_exited(child) {
+                        return Err(StartupError::ChildExited(reap_child(child)));
+                    }
+                }
+                _ => forward_signal(child, options, &sig),
+            }
+        }
+    }
+}
+
+/// Formats the name of a check program for log messages
+/// when there is more than one, as ` [PROGRAM]`.
+/// Formats as nothing when there is only one check program.
+struct CheckName<'a>(&'a Options, &'a [OsString]);
+
+impl fmt::Display for CheckName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.also_check.is_empty() {
+            Ok(())
+        } else {
+            write!(f, " [{}]", self.1[0].to_string_lossy())
+        }
+    }
+}
+
+/// Ask the service manager to extend its startup timeout
+/// to `extension` from now.
+fn extend_timeout(notify: &SystemdNotify, extension: Duration) {
+    let usec = i64::try_from(extension.as_micros()).unwrap_or(i64::MAX);
+    match notify.notify(format!("EXTEND_TIMEOUT_USEC={}", usec)) {
+        Ok(()) => log::debug!(
+            "extend_timeout",
+            usec = usec;
+            "sent EXTEND_TIMEOUT_USEC={}",
+            usec
+        ),
+        // This is sent before every attempt,
+        // so only warn about the first of a run of failures.
+        Err(e) => log::log(
+            if notify.failures() > 1 {
+                log::Level::Debug
+            } else {
+                log::Level::Warn
+            },
+            "extend_timeout",
+            &[
+                ("error", e.to_string().as_str().into()),
+                ("failures", notify.failures().into()),
+            ],
+            format_args!("sending EXTEND_TIMEOUT_USEC: {}", e),
+        ),
+    }
+}
+
+/// Result of a single health check attempt.
+#[derive(Debug)]
+enum CheckResult {
+    Passed(Readiness),
+    Failed,
+    /// The check exited with one of the `--check-fail-exit` codes.
+    Aborted(i32),
+    /// The check was killed by a signal.
+    Killed(ExitStatus),
+    SpawnFailed(io::Error),
+    /// health-notify received SIGTERM or SIGINT while the checks were running,
+    /// or the attempt's deadline passed,
+    /// so they were stopped without a result.
+    Interrupted,
+}
+
+/// Wait for `delay`, then run every check program once.
+/// Signals received in the meantime are forwarded to the child program.
+/// Returns `Err` with the child program's exit code if it exits first.
+/// If `deadline` passes first, the attempt is interrupted.
+/// The result is counted in `--metrics-file` unless the attempt was interrupted.
+fn run_check(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    signals: &mut Signals,
+    delay: Duration,
+    deadline: Option<Instant>,
+    attempt: u32,
+) -> Result<CheckResult, i32> {
+    let result = run_check_attempt(
+        child,
+        options,
+        credentials,
+        signals,
+        delay,
+        deadline,
+        attempt,
+    )?;
+    match &result {
+        CheckResult::Interrupted => {}
+        CheckResult::Passed(_) => metrics::record_check(true),
+        CheckResult::Failed
+        | CheckResult::Aborted(_)
+        | CheckResult::Killed(_)
+        | CheckResult::SpawnFailed(_) => metrics::record_check(false),
+    }
+    Ok(result)
+}
+
+fn run_check_attempt(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    signals: &mut Signals,
+    delay: Duration,
+    deadline: Option<Instant>,
+    attempt: u32,
+) -> Result<CheckResult, i32> {
+    // Wait for some period of time then start a check subprocess.
+    // We may get interrupted by signals, so this can loop.
+    let spawn_at = Instant::now() + delay;
+    loop {
+        if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            return Ok(CheckResult::Interrupted);
+        }
+        let remaining = spawn_at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let remaining = deadline.map_or(remaining, |deadline| {
+            remaining.min(deadline.saturating_duration_since(Instant::now()))
+        });
+        for sig in signals.wait_timeout(Some(remaining)) {
+            match sig.signal {
+                SIGCHLD => {
+                    if has_exited(child) {
+                        return Err(reap_child(child));
+                    }
+                }
+                _ => forward_signal(child, options, &sig),
+            }
+        }
+    }
+
+    // All checks run at once.
+    // The attempt fails as soon as any check doesn't pass,
+    // in which case the remaining checks are killed.
+    let mut running = Vec::new();
+    for check_argv in options.checks() {
+        match spawn_check(child, options, credentials, check_argv, attempt) {
+            Ok(check) => running.push(check),
+            Err(e) => {
+                stop_checks(running, options.kill_signal);
+                return Ok(CheckResult::SpawnFailed(e));
+            }
+        }
+    }
+    // The child program may have exited while the checks were being spawned.
+    // Its SIGCHLD would still wake the loop below,
+    // but there's no point in waiting for checks against a dead child.
+    if has_exited(child) {
+        let exit_code = reap_child(child);
+        stop_checks(running, options.kill_signal);
+        return Err(exit_code);
+    }
+
+    let check_deadline = options
+        .check_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let mut readiness = Readiness::Ready;
+    while !running.is_empty() {
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            log::debug!(
+                "check_interrupted",
+                attempt = attempt;
+                "stopping check attempt {} at the deadline",
+                attempt
+            );
+            stop_checks(running, Signal::SIGKILL);
+            return Ok(CheckResult::Interrupted);
+        }
+        if let (Some(timeout), Some(check_deadline)) = (options.check_timeout, check_deadline) {
+            if now >= check_deadline {
+                log::warning!(
+                    "check_result",
+                    attempt = attempt,
+                    result = "timed_out";
+                    "check attempt {} timed out after {:?}",
+                    attempt,
+                    timeout
+                );
+                stop_checks(running, Signal::SIGKILL);
+                return Ok(CheckResult::Failed);
+            }
+        }
+        let timeout = [deadline, check_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now));
+        for sig in signals.wait_timeout(timeout) {
+            match sig.signal {
+                SIGCHLD => {
+                    // Several processes may have exited with only one SIGCHLD delivered,
+                    // so poll each of them regardless of the signal's sender.
+                    // The child program exiting takes precedence over the check results.
+                    if has_exited(child) {
+                        let exit_code = reap_child(child);
+                        stop_checks(running, options.kill_signal);
+                        return Err(exit_code);
+                    }
+                    let mut i = 0;
+                    while i < running.len() {
+                        if !has_exited(&mut running[i].process) {
+                            i += 1;
+                            continue;
+                        }
+                        let mut check = running.swap_remove(i);
+                        let status = check.process.wait().ok();
+                        match check_result(options, check.argv, attempt, status, check.output) {
+                            CheckResult::Passed(Readiness::Ready) => {}
+                            CheckResult::Passed(degraded) => {
+                                if readiness == Readiness::Ready {
+                                    readiness = degraded;
+                                }
+                            }
+                            result => {
+                                stop_checks(running, options.kill_signal);
+                                return Ok(result);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    forward_signal(child, options, &sig);
+                    // The child program is likely shutting down,
+                    // so don't leave the checks running against it.
+                    if let Ok(signal @ (Signal::SIGTERM | Signal::SIGINT)) =
+                        Signal::try_from(sig.signal)
+                    {
+                        if options.defer_signals.contains(&signal) {
+                            // The child program hasn't seen it yet.
+                            continue;
+                        }
+                        log::debug!(
+                            "check_interrupted",
+                            attempt = attempt,
+                            signal = signal.as_str();
+                            "stopping check attempt {} with {}",
+                            attempt,
+                            signal
+                        );
+                        stop_checks(running, signal);
+                        return Ok(CheckResult::Interrupted);
+                    }
+                }
+            }
+        }
+    }
+    // The child program may have exited after the last check,
+    // with its SIGCHLD still pending: its exit takes precedence over the result.
+    if has_exited(child) {
+        return Err(reap_child(child));
+    }
+    Ok(CheckResult::Passed(readiness))
+}
+
+/// A check program that has been started.
+struct RunningCheck<'a> {
+    argv: &'a [OsString],
+    process: Child,
+    output: CheckOutput,
+}
+
+/// Environment variable that tells health check programs the child program's PID.
+const CHILD_PID_ENV_VAR: &str = "HEALTH_NOTIFY_CHILD_PID";
+
+/// Build the command for a health check or `--pre-check` program.
+fn check_command(
+    child: &Child,
+    options: &Options,
+    credentials: &Credentials,
+    argv: &[OsString],
+) -> Command {
+    let mut command = Command::new(options.check_program(&argv[0]));
+    command
+        .arg0(&argv[0])
+        .args(&argv[1..])
+        .env_remove(sd_notify::ENV_VAR)
+        .env(CHILD_PID_ENV_VAR, child.id().to_string());
+    credentials.for_checks().apply(&mut command);
+    if let Some(mask) = options.umask {
+        apply_umask(&mut command, mask);
+    }
+    if options.close_fds {
+        close_fds::close_on_exec(&mut command);
+    }
+    if let Some(dir) = &options.chdir {
+        command.current_dir(dir);
+    }
+    if options.user_ns {
+        user_ns::enter_on_exec(&mut command);
+    }
+    for assignment in &options.check_env {
+        assignment.apply(&mut command);
+    }
+    command
+}
+
+/// Run the `--pre-check` program to completion,
+/// forwarding signals to the child program in the meantime.
+/// If the program fails, the child program is terminated.
+/// If the child program exits first, the program is stopped with `--kill-signal`.
+fn run_pre_check(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    signals: &mut Signals,
+    argv: &[OsString],
+) -> Result<(), StartupError> {
+    let spawn_result = check_command(child, options, credentials, argv)
+        .stdin(Stdio::null())
+        .spawn();
+    let mut process = match spawn_result {
+        Ok(process) => process,
+        Err(e) => {
+            terminate_child(child, options);
+            return Err(StartupError::PreCheckSpawn(e));
+        }
+    };
+    log::debug!(
+        "pre_check_spawn",
+        pid = process.id();
+        "started --pre-check pid={}",
+        process.id()
+    );
+    loop {
+        for sig in signals.wait() {
+            match sig.signal {
+                SIGCHLD => {
+                    if has_exited(child) {
+                        let exit_code = reap_child(child);
+                        if let Ok(pid) = process.id().try_into() {
+                            let _ = kill(Pid::from_raw(pid), options.kill_signal);
+                        }
+                        let _ = process.wait();
+                        return Err(StartupError::ChildExited(exit_code));
+                    }
+                    if !has_exited(&mut process) {
+                        continue;
+                    }
+                    let status = process.wait().ok();
+                    log::info!(
+                        "pre_check_exit",
+                        exit_code = status.and_then(|s| s.code()),
+                        signal = exit_signal_name(status);
+                        "--pre-check exited ({})",
+                        DisplayStatus(status)
+                    );
+                    return match status {
+                        Some(status) if !status.success() => {
+                            terminate_child(child, options);
+                            Err(StartupError::PreCheckFailed(status))
+                        }
+                        _ => Ok(()),
+                    };
+                }
+                _ => forward_signal(child, options, &sig),
+            }
+        }
+    }
+}
+
+/// Start a health check program, logging any failure.
+fn spawn_check<'a>(
+    child: &Child,
+    options: &Options,
+    credentials: &Credentials,
+    check_argv: &'a [OsString],
+    attempt: u32,
+) -> io::Result<RunningCheck<'a>> {
+    let spawn_result = check_command(child, options, credentials, check_argv)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut process = match spawn_result {
+        Ok(process) => process,
+        Err(e) => {
+            log::warning!(
+                "check_spawn_error",
+                attempt = attempt,
+                program = check_argv[0].to_string_lossy().as_ref(),
+                error = e.to_string().as_str();
+                "check attempt {}: could not start {}: {}",
+                attempt,
+                check_argv[0].to_string_lossy(),
+                e
+            );
+            return Err(e);
+        }
+    };
+    log::debug!(
+        "check_spawn",
+        attempt = attempt,
+        program = check_argv[0].to_string_lossy().as_ref(),
+        pid = process.id();
+        "check attempt {}{} started pid={}",
+        attempt,
+        CheckName(options, check_argv),
+        process.id()
+    );
+    let output = CheckOutput::capture(&mut process);
+    Ok(RunningCheck {
+        argv: check_argv,
+        process,
+        output,
+    })
+}
+
+/// Send `signal` to check programs whose results are no longer needed
+/// and wait for them to exit.
+fn stop_checks(checks: Vec<RunningCheck>, signal: Signal) {
+    for mut check in checks {
+        if !has_exited(&mut check.process) {
+            if let Ok(pid) = check.process.id().try_into() {
+                let _ = kill(Pid::from_raw(pid), signal);
+            }
+        }
+        let _ = check.process.wait();
+    }
+}
+
+/// Interpret the exit status of a check program and log the result.
+/// The check's output is reported unless it passed.
+fn check_result(
+    options: &Options,
+    check_argv: &[OsString],
+    attempt: u32,
+    status: Option<ExitStatus>,
+    check_output: CheckOutput,
+) -> CheckResult {
+    let name = CheckName(options, check_argv);
+    let program = check_argv[0].to_string_lossy();
+    if let Some(status) = status.filter(|status| status.signal().is_some()) {
+        log::warning!(
+            "check_result",
+            attempt = attempt,
+            program = program.as_ref(),
+            result = "killed",
+            signal = exit_signal_name(Some(status));
+            "check attempt {}{} killed ({})",
+            attempt,
+            name,
+            DisplayStatus(Some(status))
+        );
+        check_output.report();
+        return CheckResult::Killed(status);
+    }
+    match status.and_then(|status| status.code()) {
+        // --check-fail-exit takes precedence over the other exit code options.
+        Some(code) if options.check_fail_exit.contains(&code) => {
+            log::warning!(
+                "check_result",
+                attempt = attempt,
+                program = program.as_ref(),
+                result = "aborted",
+                exit_code = code;
+                "check attempt {}{} reported permanent failure (exit={})",
+                attempt,
+                name,
+                code
+            );
+            check_output.report();
+            CheckResult::Aborted(code)
+        }
+        Some(code)
+            if options.check_success_exit.contains(&code)
+                || options.check_ready_exit.contains(&code) =>
+        {
+            log::info!(
+                "check_result",
+                attempt = attempt,
+                program = program.as_ref(),
+                result = "ready",
+                exit_code = code;
+                "check attempt {}{} passed",
+                attempt,
+                name
+            );
+            CheckResult::Passed(Readiness::Ready)
+        }
+        Some(code) if options.check_degraded_exit.contains(&code) => {
+            log::info!(
+                "check_result",
+                attempt = attempt,
+                program = program.as_ref(),
+                result = "degraded",
+                exit_code = code;
+                "check attempt {}{} passed degraded (exit={})",
+                attempt,
+                name,
+                code
+            );
+            CheckResult::Passed(Readiness::Degraded { exit_code: code })
+        }
+        _ => {
+            // Failures are expected while the service warms up,
+            // so only mention them at the debug level.
+            let level = if attempt <= options.warmup_failures {
+                log::Level::Debug
+            } else {
+                log::Level::Info
+            };
+            log::log(
+                level,
+                "check_result",
+                &[
+                    ("attempt", attempt.into()),
+                    ("program", program.as_ref().into()),
+                    ("result", "failed".into()),
+                    ("exit_code", status.and_then(|s| s.code()).into()),
+                    ("signal", exit_signal_name(status).into()),
+                ],
+                format_args!(
+                    "check attempt {}{} failed ({})",
+                    attempt,
+                    name,
+                    DisplayStatus(status)
+                ),
+            );
+            if level != log::Level::Debug || log::enabled(level) {
+                check_output.report();
+            }
+            CheckResult::Failed
+        }
+    }
+}
+
+/// How long `--barrier` waits for systemd to process notifications.
+const BARRIER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send READY=1, or the `--ready-payload` (and a degraded STATUS=, if applicable).
+/// Failures are logged as well as returned.
+fn send_ready(notify: &SystemdNotify, payload: &str, readiness: Readiness) -> io::Result<()> {
+    // The timestamp lets systemd order this after a preceding RELOADING=1.
+    let monotonic_usec = format!("MONOTONIC_USEC={}", sd_notify::monotonic_usec());
+    let result = match readiness {
+        Readiness::Ready => notify.notify_all(&[payload, &monotonic_usec]),
+        Readiness::Degraded { exit_code } => notify.notify_all(&[
+            payload,
+            &monotonic_usec,
+            &format!(
+                "STATUS=degraded: health check exited with code {}",
+                exit_code
+            ),
+        ]),
+    };
+    match &result {
+        Ok(()) => log::info!("ready"; "sent READY=1"),
+        Err(e) => log::warning!("ready", error = e.to_string().as_str(); "sending READY=1: {}", e),
+    }
+    result
+}
+
+/// Tell the service manager why startup failed,
+/// so that it shows up in `systemctl status`.
+fn send_startup_failure(notify: &SystemdNotify, err: &StartupError) {
+    let status = format!("STATUS=startup failed: {}", err);
+    let errno = err.errno().map(|errno| format!("ERRNO={}", errno));
+    let mut entries = vec![status.as_str()];
+    entries.extend(errno.as_deref());
+    match notify.notify_all(&entries) {
+        Ok(()) => log::debug!("startup_failure_status"; "sent {}", status),
+        Err(e) => log::warning!(
+            "startup_failure_status",
+            error = e.to_string().as_str();
+            "sending startup failure status: {}",
+            e
+        ),
+    }
+}
+
+/// Start an `--on-ready` or `--on-unhealthy` hook program
+/// with the same user, directory, and environment settings as the child program.
+/// Spawn failures are logged.
+fn spawn_hook(
+    options: &Options,
+    credentials: &Credentials,
+    name: &str,
+    argv: &[OsString],
+) -> Option<Child> {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]).stdin(Stdio::null());
+    credentials.apply(&mut command);
+    if let Some(mask) = options.umask {
+        apply_umask(&mut command, mask);
+    }
+    if options.close_fds {
+        close_fds::close_on_exec(&mut command);
+    }
+    if let Some(dir) = &options.chdir {
+        command.current_dir(dir);
+    }
+    if options.clean_env {
+        command.env_clear();
+    }
+    for assignment in &options.env {
+        assignment.apply(&mut command);
+    }
+    match command.spawn() {
+        Ok(hook) => {
+            log::debug!(
+                "hook_spawn",
+                hook = name,
+                pid = hook.id();
+                "started --{} pid={}",
+                name,
+                hook.id()
+            );
+            Some(hook)
+        }
+        Err(e) => {
+            log::warning!(
+                "hook_spawn_error",
+                hook = name,
+                error = e.to_string().as_str();
+                "could not start --{} program {}: {}",
+                name,
+                argv[0].to_string_lossy(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Wait for a hook program to exit and log its status.
+/// A hook's failure doesn't affect health-notify's own result.
+fn wait_hook(name: &str, mut hook: Child) {
+    let status = hook.wait().ok();
+    log::info!(
+        "hook_exit",
+        hook = name,
+        exit_code = status.and_then(|s| s.code()),
+        signal = exit_signal_name(status);
+        "--{} exited ({})",
+        name,
+        DisplayStatus(status)
+    );
+}
+
+/// Send each `--fd-store` descriptor to the service manager's file descriptor store.
+fn store_fds(notify: &SystemdNotify, entries: &[FdStoreEntry]) {
+    for entry in entries {
+        let payload = format!("FDSTORE=1\nFDNAME={}", entry.name);
+        match notify.notify_with_fds(payload, &[entry.fd]) {
+            Ok(()) => log::info!(
+                "fd_store",
+                name = entry.name.as_str(),
+                fd = entry.fd;
+                "stored fd {} as {}",
+                entry.fd,
+                entry.name
+            ),
+            Err(e) => log::warning!(
+                "fd_store",
+                name = entry.name.as_str(),
+                fd = entry.fd,
+                error = e.to_string().as_str();
+                "storing fd {} as {}: {}",
+                entry.fd,
+                entry.name,
+                e
+            ),
+        }
+    }
+}
+
+/// Remove each `--fd-store` descriptor from the service manager's file descriptor store,
+/// so that a clean exit doesn't leave them for the next start.
+fn remove_stored_fds(notify: &SystemdNotify, entries: &[FdStoreEntry]) {
+    for entry in entries {
+        match notify.fd_store_remove(&entry.name) {
+            Ok(()) => log::info!(
+                "fd_store_remove",
+                name = entry.name.as_str();
+                "removed {} from the fd store",
+                entry.name
+            ),
+            Err(e) => log::warning!(
+                "fd_store_remove",
+                name = entry.name.as_str(),
+                error = e.to_string().as_str();
+                "removing {} from the fd store: {}",
+                entry.name,
+                e
+            ),
+        }
+    }
+}
+
+/// Reason that [`propagate_signals`] returned.
+#[derive(Clone, Copy, Debug)]
+enum Stopped {
+    /// The child program exited with the given code.
+    ChildExited(i32),
+    /// Liveness checks failed `--liveness-failures` times in a row
+    /// (or a check exited with a `--check-fail-exit` code).
+    /// The child program has been terminated.
+    Unhealthy,
+}
+
+/// Forward signals to the child program until it exits,
+/// running liveness checks in the meantime if `--liveness-interval` was given.
+/// `attempts` is the number of health checks run so far.
+/// `proxy` is given for `--proxy-notify`.
+fn propagate_signals(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    notify: Option<&SystemdNotify>,
+    proxy: Option<&NotifyProxy>,
+    signals: &mut Signals,
+    mut attempts: u32,
+) -> Stopped {
+    let mut next_check = options
+        .liveness_interval
+        .map(|interval| Instant::now() + options.jittered(interval));
+    let mut liveness_failures = 0u32;
+    for origin in deferred_signals::release() {
+        forward_signal(child, options, &origin);
+    }
     loop {
-        for sig in signals.wait() {
-            match sig.signal {
-                SIGCHLD => {
-                    if sig.process.and_then(|p| u32::try_from(p.pid).ok()) == Some(child.id()) {
-                        return child
-                            .wait()
-                            .ok()
-                            .and_then(|status| status.code())
-                            .unwrap_or(1);
+        let timeout = next_check.map(|t| t.saturating_duration_since(Instant::now()));
+        for sig in signals.wait_timeout(timeout) {
+            match (sig.signal, notify) {
+                (SIGCHLD, _) => {
+                    if has_exited(child) {
+                        return Stopped::ChildExited(reap_child(child));
                     }
                 }
-                _ => {
-                    let _ = kill(
-                        Pid::from_raw(child.id().try_into().unwrap()),
-                        nix::sys::signal::Signal::try_from(sig.signal).unwrap(),
+                (SIGHUP, Some(notify)) if options.reload_on_hup => {
+                    send_reloading(notify);
+                    if let Some(proxy) = proxy {
+                        // The proxy relays the child's own READY=1 when it finishes reloading.
+                        proxy.start_reload();
+                        forward_signal(child, options, &sig);
+                        continue;
+                    }
+                    attempts += 1;
+                    if let Err(exit_code) =
+                        reload(child, options, credentials, notify, signals, &sig, attempts)
+                    {
+                        return Stopped::ChildExited(exit_code);
+                    }
+                }
+                _ => forward_signal(child, options, &sig),
+            }
+        }
+
+        let (Some(interval), Some(t)) = (options.liveness_interval, next_check) else {
+            continue;
+        };
+        if Instant::now() < t {
+            continue;
+        }
+        attempts += 1;
+        match run_check(
+            child,
+            options,
+            credentials,
+            signals,
+            Duration::ZERO,
+            None,
+            attempts,
+        ) {
+            Err(exit_code) => return Stopped::ChildExited(exit_code),
+            Ok(CheckResult::Passed(_)) => liveness_failures = 0,
+            Ok(CheckResult::Interrupted) => {}
+            Ok(CheckResult::Aborted(_)) => {
+                terminate_child(child, options);
+                return Stopped::Unhealthy;
+            }
+            Ok(CheckResult::Failed | CheckResult::Killed(_) | CheckResult::SpawnFailed(_)) => {
+                liveness_failures += 1;
+                if liveness_failures >= options.liveness_failures {
+                    log::warning!(
+                        "unhealthy",
+                        failures = liveness_failures;
+                        "{} liveness checks failed in a row",
+                        liveness_failures
                     );
+                    terminate_child(child, options);
+                    return Stopped::Unhealthy;
+                }
+            }
+        }
+        next_check = Some(Instant::now() + options.jittered(interval));
+    }
+}
+
+/// Send RELOADING=1 to start the `--reload-on-hup` handshake.
+fn send_reloading(notify: &SystemdNotify) {
+    let monotonic_usec = format!("MONOTONIC_USEC={}", sd_notify::monotonic_usec());
+    match notify.notify_all(&["RELOADING=1", &monotonic_usec]) {
+        Ok(()) => log::info!("reloading"; "sent RELOADING=1"),
+        Err(e) => log::warning!(
+            "reloading",
+            error = e.to_string().as_str();
+            "sending RELOADING=1: {}",
+            e
+        ),
+    }
+}
+
+/// Finish the `--reload-on-hup` handshake without `--proxy-notify`
+/// after RELOADING=1 has been sent:
+/// forward SIGHUP to the child program
+/// and send READY=1 once a health check passes.
+/// If the check fails, systemd is left in the reloading state.
+/// Returns `Err` with the child program's exit code if it exits during the check.
+fn reload(
+    child: &mut Child,
+    options: &Options,
+    credentials: &Credentials,
+    notify: &SystemdNotify,
+    signals: &mut Signals,
+    sighup: &Origin,
+    attempt: u32,
+) -> Result<(), i32> {
+    forward_signal(child, options, sighup);
+    match run_check(
+        child,
+        options,
+        credentials,
+        signals,
+        options.interval,
+        None,
+        attempt,
+    )? {
+        CheckResult::Passed(readiness) => {
+            let _ = send_ready(notify, options.ready_payload(), readiness);
+        }
+        CheckResult::Interrupted => {}
+        CheckResult::Failed
+        | CheckResult::Aborted(_)
+        | CheckResult::Killed(_)
+        | CheckResult::SpawnFailed(_) => {
+            log::warning!(
+                "reload_failed",
+                attempt = attempt;
+                "health check failed after reload; leaving systemd in the reloading state"
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the child program to exit and return its exit code.
+fn reap_child(child: &mut Child) -> i32 {
+    // Child::wait retries waitpid(2) on EINTR,
+    // so an error means the status is really gone
+    // (for example, ECHILD because something else reaped the child).
+    let status = match child.wait() {
+        Ok(status) => Some(status),
+        Err(e) => {
+            log::warning!(
+                "child_wait",
+                pid = child.id(),
+                error = e.to_string().as_str();
+                "waiting for child pid={}: {}",
+                child.id(),
+                e
+            );
+            None
+        }
+    };
+    log::info!(
+        "child_exit",
+        pid = child.id(),
+        exit_code = status.and_then(|s| s.code()),
+        signal = exit_signal_name(status);
+        "child exited ({})",
+        DisplayStatus(status)
+    );
+    status.map_or(1, shell_exit_code)
+}
+
+/// Reports whether `child` has exited, without blocking.
+/// Its exit status stays available to [`Child::wait`].
+///
+/// SIGCHLD is not queued, so when several processes exit at once,
+/// a single signal may be delivered for all of them.
+/// Callers should poll every process they're waiting on when any SIGCHLD arrives
+/// rather than trusting the signal's sender.
+fn has_exited(child: &mut Child) -> bool {
+    loop {
+        match child.try_wait() {
+            Ok(status) => return status.is_some(),
+            // waitpid(2) with WNOHANG doesn't block,
+            // but don't mistake an interrupted call for an exit.
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Any other error means the process can't be waited on,
+            // so treat it as exited instead of waiting forever.
+            Err(_) => return true,
+        }
+    }
+}
+
+/// Convert an exit status to the code a shell would report:
+/// the exit code if the process exited normally,
+/// or 128 plus the signal number if it was killed by a signal.
+fn shell_exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// Stop the child program with `--stop-sequence` (or `--kill-signal`) and wait for it to exit.
+fn terminate_child(child: &mut Child, options: &Options) {
+    if let Some(target) = signal_target(child, options) {
+        let pidfd = match PidFd::open(child.id()) {
+            Ok(pidfd) => Some(pidfd),
+            Err(e) => {
+                log::warning!(
+                    "pidfd_unavailable",
+                    error = e.to_string().as_str();
+                    "can't open a pidfd for the child ({}); polling for its exit instead",
+                    e
+                );
+                None
+            }
+        };
+        options
+            .stop_sequence()
+            .run(child.id(), pidfd.as_ref(), target);
+    }
+    reap_child(child);
+}
+
+/// Forward a signal that health-notify received to the child program.
+/// With `--no-forward-signals`, the signal is only logged.
+/// A `--defer-signal` signal is held until the child program is ready.
+///
+/// The signal is sent with kill(2),
+/// so the child program sees health-notify as the sender, not `origin.process`.
+fn forward_signal(child: &Child, options: &Options, origin: &Origin) {
+    let sender = origin.process.as_ref().map(|p| p.pid);
+    let Ok(signal) = Signal::try_from(origin.signal) else {
+        log::warning!(
+            "signal_unknown",
+            signal = origin.signal,
+            sender_pid = sender;
+            "received unknown signal {}{}; not forwarding",
+            origin.signal,
+            DisplaySender(sender)
+        );
+        return;
+    };
+    let Some(target) = signal_target(child, options) else {
+        // A PID that doesn't fit in pid_t can't belong to a live process.
+        log::debug!("signal_drop", signal = signal.as_str(); "child is gone; dropping {}", signal);
+        return;
+    };
+    if options.no_forward_signals {
+        log::debug!(
+            "signal_drop",
+            signal = signal.as_str(),
+            sender_pid = sender;
+            "not forwarding {}{} (--no-forward-signals)",
+            signal,
+            DisplaySender(sender)
+        );
+        return;
+    }
+    if options.defer_signals.contains(&signal) && deferred_signals::hold(origin) {
+        log::info!(
+            "signal_defer",
+            signal = signal.as_str(),
+            sender_pid = sender;
+            "deferring {}{} until child is ready",
+            signal,
+            DisplaySender(sender)
+        );
+        return;
+    }
+    // Terminal resizes can arrive in bursts, so keep them out of -v.
+    let level = if signal == Signal::SIGWINCH {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log(
+        level,
+        "signal_forward",
+        &[
+            ("pid", child.id().into()),
+            ("signal", signal.as_str().into()),
+            ("sender_pid", sender.into()),
+        ],
+        format_args!("forwarding {}{} to child", signal, DisplaySender(sender)),
+    );
+    let _ = kill(target, signal);
+    if signal == Signal::SIGTSTP {
+        // Catching SIGTSTP replaced its default action of stopping us,
+        // so stop now to let the shell see the job stop.
+        // SIGCONT resumes us.
+        let _ = kill(Pid::this(), Signal::SIGSTOP);
+    }
+}
+
+/// Formats the PID of the process that sent a signal as " from pid=N",
+/// or nothing if the kernel didn't report a sender.
+struct DisplaySender(Option<pid_t>);
+
+impl fmt::Display for DisplaySender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(pid) => write!(f, " from pid={}", pid),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the name of the signal that terminated a process, if any.
+fn exit_signal_name(status: Option<ExitStatus>) -> Option<&'static str> {
+    status
+        .and_then(|status| status.signal())
+        .and_then(|signal| Signal::try_from(signal).ok())
+        .map(Signal::as_str)
+}
+
+/// Formats an exit status for logs as `exit=N` or `signal=SIGNAME`.
+struct DisplayStatus(Option<ExitStatus>);
+
+impl fmt::Display for DisplayStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(status) = self.0 else {
+            return f.write_str("status unknown");
+        };
+        if let Some(code) = status.code() {
+            write!(f, "exit={}", code)
+        } else if let Some(signal) = status.signal() {
+            match Signal::try_from(signal) {
+                Ok(signal) => write!(f, "signal={}", signal),
+                Err(_) => write!(f, "signal={}", signal),
+            }
+        } else {
+            write!(f, "{}", status)
+        }
+    }
+}
+
+/// Returns the process ID that forwarded signals should be sent to:
+/// the child program itself or, with `--process-group`,
+/// the negated process group ID so that the whole group receives it.
+/// The child is always the leader of its group (or session, with `--setsid`),
+/// so the group ID is the same as the child's PID.
+/// Returns `None` if the child's PID can't be represented as a `pid_t`.
+fn signal_target(child: &Child, options: &Options) -> Option<Pid> {
+    let pid: i32 = child.id().try_into().ok()?;
+    if options.process_group {
+        Some(Pid::from_raw(-pid))
+    } else {
+        Some(Pid::from_raw(pid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use nix::libc::c_int;
+
+    use super::*;
+
+    /// Bind a datagram socket at a temporary path to stand in for the service manager.
+    fn manager_socket(name: &str) -> (UnixDatagram, PathBuf) {
+        let path = env::temp_dir().join(format!("health-notify-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    /// Receive one notification as a list of lines.
+    fn recv_lines(socket: &UnixDatagram) -> Vec<String> {
+        let mut buf = vec![0u8; 4096];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Parse command-line arguments, not including the program name.
+    fn try_parse(args: &[&str]) -> Result<Options, clap::Error> {
+        let mut matches =
+            Options::command().try_get_matches_from(["health-notify"].iter().chain(args))?;
+        Options::from_arg_matches_mut(&mut matches)
+    }
+
+    fn parse(args: &[&str]) -> Options {
+        try_parse(args).unwrap()
+    }
+
+    #[test]
+    fn custom_separator() {
+        let args = [
+            "health-notify",
+            "--separator",
+            "END",
+            "sh",
+            "-c",
+            "a; b",
+            "END",
+            "true",
+        ];
+        let options = parse_args(&args.map(OsString::from)).unwrap();
+        assert_eq!(options.child_argv, ["sh", "-c", "a; b"]);
+        assert_eq!(options.check_argv, ["true"]);
+    }
+
+    #[test]
+    fn help_shows_separator() {
+        let help = Options::command_with_separator(DEFAULT_SEPARATOR)
+            .render_help()
+            .to_string();
+        assert!(help.contains("\\; CHECK_PROGRAM"), "{}", help);
+        assert!(help.contains("terminated by ';'"), "{}", help);
+
+        let help = Options::command_with_separator("END")
+            .render_help()
+            .to_string();
+        assert!(help.contains("END CHECK_PROGRAM"), "{}", help);
+        assert!(help.contains("terminated by 'END'"), "{}", help);
+        assert!(!help.contains("terminated by ';'"), "{}", help);
+
+        let args = ["health-notify", "--separator", "END", "--help"].map(OsString::from);
+        assert_eq!(find_separator(&args), "END");
+    }
+
+    #[test]
+    fn command_line_replaces_config_values() {
+        let dir = temp_dir("config");
+        let path = dir.join("health-notify.toml");
+        fs::write(
+            &path,
+            "env = ['A=1', 'B=2']\nverbose = 2\nforward-signal = ['USR1']\n",
+        )
+        .unwrap();
+        let args = |extra: &[&str]| {
+            let mut args = vec!["health-notify", "--config", path.to_str().unwrap()];
+            args.extend(extra);
+            args.extend(["sleep", "60", ";", "true"]);
+            parse_args(&args.into_iter().map(OsString::from).collect::<Vec<_>>()).unwrap()
+        };
+
+        let options = args(&[]);
+        assert_eq!(options.env.len(), 2);
+        assert_eq!(options.verbose, 2);
+        assert_eq!(options.forward_signals, [Signal::SIGUSR1]);
+
+        let options = args(&["--env", "C=3", "-v"]);
+        assert!(
+            matches!(&options.env[..], [EnvAssignment::Set(key, value)] if key == "C" && value == "3"),
+            "{:?}",
+            options.env
+        );
+        assert_eq!(options.verbose, 1);
+        assert_eq!(options.forward_signals, [Signal::SIGUSR1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timing_options_take_units() {
+        let options = parse(&[
+            "--interval",
+            "250ms",
+            "--initial-delay",
+            "2",
+            "--startup-timeout",
+            "2m",
+            "--check-timeout",
+            "5s",
+            "--kill-timeout",
+            "1h",
+            "sleep",
+            "60",
+            ";",
+            "true",
+        ]);
+        assert_eq!(options.interval, Duration::from_millis(250));
+        assert_eq!(options.initial_delay, Some(Duration::from_secs(2)));
+        assert_eq!(options.startup_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(options.check_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(options.kill_timeout, Duration::from_secs(3600));
+
+        let err = try_parse(&["--check-timeout", "5x", "sleep", "60", ";", "true"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+        assert!(err.to_string().contains("--check-timeout"), "{}", err);
+        let err = try_parse(&["--kill-timeout", "", "sleep", "60", ";", "true"]).unwrap_err();
+        assert!(err.to_string().contains("--kill-timeout"), "{}", err);
+    }
+
+    #[test]
+    fn startup_exit_codes() {
+        let timeout = StartupError::ReadyTimeout(Duration::from_secs(1));
+        let aborted = StartupError::CheckAborted(2);
+        let exited = StartupError::ChildExited(3);
+
+        let options = parse(&["sleep", "60", ";", "true"]);
+        assert_eq!(startup_exit_code(&options, &timeout), 1);
+        assert_eq!(startup_exit_code(&options, &aborted), 1);
+        assert_eq!(startup_exit_code(&options, &exited), 3);
+
+        let options = parse(&["--exit-code-on-unhealthy", "10", "sleep", "60", ";", "true"]);
+        assert_eq!(startup_exit_code(&options, &timeout), 10);
+        assert_eq!(startup_exit_code(&options, &aborted), 10);
+        assert_eq!(startup_exit_code(&options, &exited), 3);
+
+        let options = parse(&[
+            "--exit-code-on-unhealthy",
+            "10",
+            "--exit-code-on-timeout",
+            "20",
+            "sleep",
+            "60",
+            ";",
+            "true",
+        ]);
+        let startup_timeout = StartupError::StartupTimeout(Duration::from_secs(1));
+        assert_eq!(startup_exit_code(&options, &timeout), 20);
+        assert_eq!(startup_exit_code(&options, &startup_timeout), 20);
+        assert_eq!(startup_exit_code(&options, &aborted), 10);
+        assert_eq!(startup_exit_code(&options, &exited), 3);
+    }
+
+    #[test]
+    fn stop_sequence_defaults_to_sigkill_after_timeout() {
+        let options = parse(&["sleep", "60", ";", "true"]);
+        assert_eq!(
+            options.stop_sequence(),
+            StopSequence::escalating(Signal::SIGTERM, Duration::from_secs(90))
+        );
+
+        let options = parse(&[
+            "--kill-signal",
+            "INT",
+            "--kill-timeout",
+            "5s",
+            "sleep",
+            "60",
+            ";",
+            "true",
+        ]);
+        assert_eq!(
+            options.stop_sequence(),
+            StopSequence::escalating(Signal::SIGINT, Duration::from_secs(5))
+        );
+
+        let options = parse(&["--stop-sequence", "TERM", "sleep", "60", ";", "true"]);
+        assert_eq!(
+            options.stop_sequence(),
+            StopSequence::single(Signal::SIGTERM)
+        );
+
+        let err = try_parse(&[
+            "--kill-timeout",
+            "5s",
+            "--stop-sequence",
+            "TERM",
+            "sleep",
+            "60",
+            ";",
+            "true",
+        ])
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn missing_separator_is_explained() {
+        let err = try_parse(&["sleep", "60", "true"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+        let message = err.to_string();
+        assert!(
+            message.contains("no health check program given"),
+            "{}",
+            message
+        );
+        assert!(message.contains("must be followed by `;`"), "{}", message);
+        assert!(
+            message.contains("health-notify sleep 60 \\; true"),
+            "{}",
+            message
+        );
+
+        let options = parse(&["sleep", "60", ";", "true"]);
+        assert_eq!(options.child_argv, ["sleep", "60"]);
+        assert_eq!(options.check_argv, ["true"]);
+    }
+
+    /// Build an `Origin` for `signal` as though the kernel had sent it.
+    fn kernel_origin(signal: c_int) -> Origin {
+        // SI_KERNEL from <asm-generic/siginfo.h>, which libc doesn't export.
+        const SI_KERNEL: c_int = 0x80;
+        // SAFETY: siginfo_t is a plain C struct for which all zeroes is a valid value,
+        // and SI_KERNEL tells extract not to read the sender fields.
+        unsafe {
+            let mut info: nix::libc::siginfo_t = std::mem::zeroed();
+            info.si_signo = signal;
+            info.si_code = SI_KERNEL;
+            Origin::extract(&info)
+        }
+    }
+
+    #[test]
+    fn forward_signal_skips_unknown_signal() {
+        let options = parse(&["sleep", "10", ";", "true"]);
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        forward_signal(&child, &options, &kernel_origin(nix::libc::SIGRTMAX()));
+        assert!(child.try_wait().unwrap().is_none());
+
+        forward_signal(&child, &options, &kernel_origin(Signal::SIGTERM as c_int));
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as c_int));
+    }
+
+    /// Create an empty temporary directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("health-notify-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Create a file at `path` with the given permission bits.
+    fn create_file(path: &Path, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn resolve_program_relative_to_dir() {
+        let dir = temp_dir("resolve-dir");
+        fs::create_dir(dir.join("bin")).unwrap();
+        create_file(&dir.join("bin/tool"), 0o755);
+        assert_eq!(
+            resolve_program("bin/tool".as_ref(), Some(&dir), None).unwrap(),
+            dir.join("bin/tool")
+        );
+
+        // A relative --chdir is relative to our working directory.
+        let cwd = env::current_dir().unwrap();
+        let up = "../".repeat(cwd.components().count() - 1);
+        let relative_dir = PathBuf::from(up).join(dir.strip_prefix("/").unwrap());
+        let resolved = resolve_program("bin/tool".as_ref(), Some(&relative_dir), None).unwrap();
+        assert!(resolved.is_absolute(), "{}", resolved.display());
+        assert_eq!(resolved, cwd.join(&relative_dir).join("bin/tool"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_program_rejects_unusable_paths() {
+        let dir = temp_dir("resolve-errors");
+        create_file(&dir.join("data"), 0o644);
+        // Even root needs an execute bit.
+        assert!(resolve_program("./data".as_ref(), Some(&dir), None).is_err());
+        let err = resolve_program("./".as_ref(), Some(&dir), None).unwrap_err();
+        assert!(err.to_string().ends_with("not a regular file"), "{}", err);
+        assert!(resolve_program("./missing".as_ref(), Some(&dir), None).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_program_searches_path() {
+        let dir = temp_dir("resolve-path");
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+        create_file(&dir.join("a/tool"), 0o644);
+        create_file(&dir.join("b/tool"), 0o755);
+        let search_path = env::join_paths([dir.join("a"), dir.join("b")]).unwrap();
+        assert_eq!(
+            resolve_program("tool".as_ref(), None, Some(&search_path)).unwrap(),
+            dir.join("b/tool")
+        );
+        let err = resolve_program("missing".as_ref(), None, Some(&search_path)).unwrap_err();
+        assert_eq!(err.to_string(), "missing: not found in PATH");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Returns the value of the `MONOTONIC_USEC=` line in a notification.
+    fn monotonic_usec(lines: &[String]) -> u64 {
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix("MONOTONIC_USEC="))
+            .expect("MONOTONIC_USEC= present")
+            .parse()
+            .expect("MONOTONIC_USEC= is an integer")
+    }
+
+    #[test]
+    fn reload_notifications_carry_monotonic_usec() {
+        let (manager, path) = manager_socket("monotonic");
+        let notify = SystemdNotify::from_path(&path);
+        let before = sd_notify::monotonic_usec();
+        send_reloading(&notify);
+        let reloading = recv_lines(&manager);
+        send_ready(&notify, "READY=1", Readiness::Ready).unwrap();
+        let ready = recv_lines(&manager);
+        let after = sd_notify::monotonic_usec();
+
+        assert_eq!(reloading[0], "RELOADING=1");
+        let reloading_usec = monotonic_usec(&reloading);
+        let ready_usec = monotonic_usec(&ready);
+        assert!(before <= reloading_usec, "{} > {}", before, reloading_usec);
+        assert!(
+            reloading_usec <= ready_usec,
+            "{} > {}",
+            reloading_usec,
+            ready_usec
+        );
+        assert!(ready_usec <= after, "{} > {}", ready_usec, after);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_ready_degraded_includes_status() {
+        let (manager, path) = manager_socket("degraded");
+        let notify = sd_notify::SystemdNotify::from_path(&path);
+        send_ready(&notify, "READY=1", Readiness::Degraded { exit_code: 3 }).unwrap();
+        let lines = recv_lines(&manager);
+        assert_eq!(lines[0], "READY=1");
+        assert!(lines.contains(&"STATUS=degraded: health check exited with code 3".to_string()));
+
+        send_ready(&notify, "READY=1", Readiness::Ready).unwrap();
+        let lines = recv_lines(&manager);
+        assert_eq!(lines[0], "READY=1");
+        assert!(!lines.iter().any(|line| line.starts_with("STATUS=")));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shell_exit_code_passes_exit_code_through() {
+        assert_eq!(shell_exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(shell_exit_code(ExitStatus::from_raw(3 << 8)), 3);
+    }
+
+    /// Kill a running child program with `signal`
+    /// and return the exit code that health-notify would exit with.
+    fn exit_code_after_signal(signal: Signal) -> i32 {
+        let _lock = lock_signals();
+        // No core file for the signals that would dump one.
+        let (options, credentials) =
+            check_options(&["sh", "-c", "ulimit -c 0; exec sleep 60"], &["true"]);
+        let mut child = spawn_child(&options);
+        let mut signals = test_signals();
+        kill(Pid::from_raw(child.id().try_into().unwrap()), signal).unwrap();
+        match propagate_signals(
+            &mut child,
+            &options,
+            &credentials,
+            None,
+            None,
+            &mut signals,
+            1,
+        ) {
+            Stopped::ChildExited(exit_code) => exit_code,
+            stopped => panic!("unexpected result {:?}", stopped),
+        }
+    }
+
+    #[test]
+    fn child_killed_by_sigkill_exits_137() {
+        assert_eq!(exit_code_after_signal(Signal::SIGKILL), 137);
+    }
+
+    #[test]
+    fn child_killed_by_sigsegv_exits_139() {
+        assert_eq!(exit_code_after_signal(Signal::SIGSEGV), 139);
+    }
+
+    /// Classify a check that exited with `status` and printed nothing.
+    fn classify(options: &Options, status: Option<ExitStatus>) -> CheckResult {
+        let mut check = Command::new("true").spawn().unwrap();
+        check.wait().unwrap();
+        let argv = [OsString::from("check")];
+        check_result(options, &argv, 1, status, CheckOutput::capture(&mut check))
+    }
+
+    fn exited(code: i32) -> Option<ExitStatus> {
+        Some(ExitStatus::from_raw(code << 8))
+    }
+
+    #[test]
+    fn check_result_default_codes() {
+        let options = parse(&["sleep", "60", ";", "true"]);
+        assert!(matches!(
+            classify(&options, exited(0)),
+            CheckResult::Passed(Readiness::Ready)
+        ));
+        assert!(matches!(classify(&options, exited(1)), CheckResult::Failed));
+        assert!(matches!(
+            classify(&options, exited(64)),
+            CheckResult::Failed
+        ));
+        assert!(matches!(classify(&options, None), CheckResult::Failed));
+        assert!(matches!(
+            classify(
+                &options,
+                Some(ExitStatus::from_raw(Signal::SIGKILL as c_int))
+            ),
+            CheckResult::Killed(_)
+        ));
+    }
+
+    #[test]
+    fn check_result_configured_codes() {
+        let options = parse(&[
+            "--check-success-exit=64",
+            "--check-success-exit=65",
+            "--check-ready-exit=66",
+            "--check-degraded-exit=3",
+            "--check-fail-exit=2",
+            "--check-fail-exit=65",
+            "sleep",
+            "60",
+            ";",
+            "true",
+        ]);
+        // --check-success-exit replaces the default of 0.
+        assert!(matches!(classify(&options, exited(0)), CheckResult::Failed));
+        assert!(matches!(
+            classify(&options, exited(64)),
+            CheckResult::Passed(Readiness::Ready)
+        ));
+        assert!(matches!(
+            classify(&options, exited(66)),
+            CheckResult::Passed(Readiness::Ready)
+        ));
+        assert!(matches!(
+            classify(&options, exited(3)),
+            CheckResult::Passed(Readiness::Degraded { exit_code: 3 })
+        ));
+        assert!(matches!(
+            classify(&options, exited(2)),
+            CheckResult::Aborted(2)
+        ));
+        // --check-fail-exit wins over --check-success-exit.
+        assert!(matches!(
+            classify(&options, exited(65)),
+            CheckResult::Aborted(65)
+        ));
+        assert!(matches!(classify(&options, exited(1)), CheckResult::Failed));
+    }
+
+    /// Serializes tests that install signal handlers,
+    /// since every [`Signals`] sees every signal sent to the test process.
+    static SIGNALS_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn lock_signals() -> std::sync::MutexGuard<'static, ()> {
+        SIGNALS_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Parse options for a child program and a check program and resolve them through PATH.
+    fn check_options(child: &[&str], check: &[&str]) -> (Options, Credentials) {
+        let args = [child, &[";"], check].concat();
+        let mut options = parse(&args);
+        resolve_programs(&mut options).unwrap();
+        let credentials = Credentials::from_options(&options).unwrap();
+        (options, credentials)
+    }
+
+    fn spawn_child(options: &Options) -> Child {
+        Command::new(&options.child_program)
+            .args(&options.child_argv[1..])
+            .spawn()
+            .unwrap()
+    }
+
+    fn test_signals() -> Signals {
+        Signals::new([SIGINT, SIGTERM, SIGUSR1, SIGCHLD]).unwrap()
+    }
+
+    #[test]
+    fn sigterm_stops_child_and_checks() {
+        let _lock = lock_signals();
+        let dir = temp_dir("sigterm");
+        let pid_file = dir.join("check.pid");
+        let script = format!("echo $$ > {}; exec sleep 60", pid_file.display());
+        let (options, credentials) = check_options(&["sleep", "60"], &["sh", "-c", &script]);
+        let mut child = spawn_child(&options);
+        let mut signals = test_signals();
+
+        let sender = thread::spawn({
+            let pid_file = pid_file.clone();
+            move || {
+                // Wait for the check to start, then signal ourselves as systemd would.
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while fs::read_to_string(&pid_file).map_or(true, |pid| !pid.ends_with('\n')) {
+                    assert!(Instant::now() < deadline, "check didn't start");
+                    thread::sleep(Duration::from_millis(10));
+                }
+                kill(Pid::this(), Signal::SIGTERM).unwrap();
+            }
+        });
+        let result = run_check_attempt(
+            &mut child,
+            &options,
+            &credentials,
+            &mut signals,
+            Duration::ZERO,
+            None,
+            1,
+        );
+        sender.join().unwrap();
+        assert!(
+            matches!(result, Ok(CheckResult::Interrupted)),
+            "{:?}",
+            result
+        );
+
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as c_int));
+        let check_pid = fs::read_to_string(&pid_file).unwrap();
+        let check_pid = Pid::from_raw(check_pid.trim().parse().unwrap());
+        // The check was stopped and reaped, so its PID is gone.
+        assert_eq!(kill(check_pid, None), Err(nix::errno::Errno::ESRCH));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn child_and_check_exit_together() {
+        let _lock = lock_signals();
+        // The check kills the child and exits as soon as the child is a zombie,
+        // so both usually exit before the check loop wakes up.
+        let script = "kill -KILL $HEALTH_NOTIFY_CHILD_PID; \
+                      until [ \"$(cut -d' ' -f3 /proc/$HEALTH_NOTIFY_CHILD_PID/stat)\" = Z ]; do :; done";
+        let (options, credentials) = check_options(&["sleep", "60"], &["sh", "-c", script]);
+        for _ in 0..20 {
+            let mut child = spawn_child(&options);
+            let mut signals = test_signals();
+            let result = run_check_attempt(
+                &mut child,
+                &options,
+                &credentials,
+                &mut signals,
+                Duration::ZERO,
+                None,
+                1,
+            );
+            assert!(matches!(result, Err(137)), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn checks_wait_for_first_notify() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let _lock = lock_signals();
+        let dir = temp_dir("first-notify");
+        let sent = dir.join("sent");
+        let (options, credentials) =
+            check_options(&["sleep", "60"], &["test", "-e", sent.to_str().unwrap()]);
+        let (proxy, wakeup) = NotifyProxy::start(None).unwrap();
+        let mut signals = test_signals();
+        signals.add_wakeup(wakeup).unwrap();
+        let mut child = spawn_child(&options);
+        // The child shares our process group, so our messages count as its own.
+        proxy.set_child(child.id());
+
+        let name = proxy.address().as_encoded_bytes()[1..].to_vec();
+        let sender = thread::spawn({
+            let sent = sent.clone();
+            move || {
+                thread::sleep(Duration::from_millis(200));
+                fs::write(&sent, "").unwrap();
+                let addr = SocketAddr::from_abstract_name(name).unwrap();
+                UnixDatagram::unbound()
+                    .unwrap()
+                    .send_to_addr(b"STATUS=starting", &addr)
+                    .unwrap();
+            }
+        });
+        wait_for_first_notify(&mut child, &options, &proxy, &mut signals).unwrap();
+        // The check only passes if it runs after the child's STATUS= was sent.
+        let result = run_check_attempt(
+            &mut child,
+            &options,
+            &credentials,
+            &mut signals,
+            Duration::ZERO,
+            None,
+            1,
+        );
+        sender.join().unwrap();
+        assert!(
+            matches!(result, Ok(CheckResult::Passed(Readiness::Ready))),
+            "{:?}",
+            result
+        );
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_timeout_fails_attempt() {
+        let _lock = lock_signals();
+        let (mut options, credentials) = check_options(&["sleep", "60"], &["sleep", "60"]);
+        options.check_timeout = Some(Duration::from_millis(100));
+        let mut child = spawn_child(&options);
+        let mut signals = test_signals();
+        let start = Instant::now();
+        let result = run_check_attempt(
+            &mut child,
+            &options,
+            &credentials,
+            &mut signals,
+            Duration::ZERO,
+            None,
+            1,
+        );
+        assert!(matches!(result, Ok(CheckResult::Failed)), "{:?}", result);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // The child program is left alone.
+        assert!(!has_exited(&mut child));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn startup_timeout_terminates_child() {
+        let _lock = lock_signals();
+        let (mut options, credentials) = check_options(&["sleep", "60"], &["sleep", "60"]);
+        options.interval = Duration::from_millis(50);
+        options.startup_timeout = Some(Duration::from_millis(300));
+        let mut child = spawn_child(&options);
+        let mut signals = test_signals();
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = wait_for_startup(
+            &mut child,
+            &options,
+            &credentials,
+            None,
+            &mut signals,
+            start,
+            &mut attempts,
+        );
+        // The hung check is stopped at the deadline too.
+        assert!(
+            matches!(result, Err(StartupError::StartupTimeout(_))),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(attempts, 1);
+        let status = child.try_wait().unwrap().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as c_int));
+    }
+
+    /// Send SIGUSR1 to this process every millisecond until the returned flag is set.
+    fn signal_storm() -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let sender = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let _ = kill(Pid::this(), Signal::SIGUSR1);
+                    thread::sleep(Duration::from_millis(1));
                 }
             }
+        });
+        (stop, sender)
+    }
+
+    /// Wait until `child` ignores `signal`, as shown by `SigIgn` in its status.
+    fn wait_for_ignored(child: &Child, signal: Signal) {
+        let bit = 1u64 << (signal as c_int - 1);
+        let status_path = format!("/proc/{}/status", child.id());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let status = fs::read_to_string(&status_path).unwrap();
+            let ignored = status
+                .lines()
+                .find_map(|line| line.strip_prefix("SigIgn:"))
+                .map(|mask| u64::from_str_radix(mask.trim(), 16).unwrap())
+                .unwrap();
+            if ignored & bit != 0 {
+                return;
+            }
+            assert!(Instant::now() < deadline, "child never ignored {}", signal);
+            thread::sleep(Duration::from_millis(1));
         }
     }
+
+    #[test]
+    fn child_exit_survives_signal_storm() {
+        let _lock = lock_signals();
+        let (options, credentials) = check_options(
+            &["sh", "-c", "trap '' USR1; sleep 0.3; exit 5"],
+            &["sleep", "60"],
+        );
+        let mut child = spawn_child(&options);
+        let mut signals = test_signals();
+        // Signals are forwarded to the child, so wait for its trap before the storm.
+        wait_for_ignored(&child, Signal::SIGUSR1);
+        let (stop, sender) = signal_storm();
+        let result = run_check_attempt(
+            &mut child,
+            &options,
+            &credentials,
+            &mut signals,
+            Duration::ZERO,
+            None,
+            1,
+        );
+        stop.store(true, Ordering::Relaxed);
+        sender.join().unwrap();
+        assert!(matches!(result, Err(5)), "{:?}", result);
+    }
+
+    #[test]
+    fn reap_child_survives_signal_storm() {
+        let _lock = lock_signals();
+        // Keep SIGUSR1 from killing the test process.
+        let _signals = test_signals();
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 0.2; exit 6"])
+            .spawn()
+            .unwrap();
+        let (stop, sender) = signal_storm();
+        let exit_code = reap_child(&mut child);
+        stop.store(true, Ordering::Relaxed);
+        sender.join().unwrap();
+        assert_eq!(exit_code, 6);
+    }
+
+    #[test]
+    fn check_has_default_sigpipe() {
+        let (options, credentials) = check_options(&["sleep", "60"], &["yes"]);
+        let mut child = spawn_child(&options);
+        let mut check = check_command(&child, &options, &credentials, &options.check_argv)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Close the reading end early, like `head` does.
+        let mut stdout = check.stdout.take().unwrap();
+        stdout.read_exact(&mut [0u8; 16]).unwrap();
+        drop(stdout);
+        let status = check.wait().unwrap();
+        // Our own SIGPIPE is ignored, but the check must not inherit that.
+        assert_eq!(status.signal(), Some(Signal::SIGPIPE as c_int));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn check_attempt_notices_child_that_already_exited() {
+        use nix::libc;
+
+        let _lock = lock_signals();
+        let (options, credentials) = check_options(&["sh", "-c", "exit 7"], &["sleep", "60"]);
+        let mut child = spawn_child(&options);
+        // Wait for the child to exit without reaping it,
+        // before installing the handlers so that no SIGCHLD wakes the check loop.
+        // SAFETY: siginfo_t is a plain C struct for which all zeroes is a valid value.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        // SAFETY: info is valid for waitid to write to.
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id(),
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        assert_eq!(result, 0);
+        let mut signals = test_signals();
+
+        let start = Instant::now();
+        let result = run_check_attempt(
+            &mut child,
+            &options,
+            &credentials,
+            &mut signals,
+            Duration::ZERO,
+            None,
+            1,
+        );
+        assert!(matches!(result, Err(7)), "{:?}", result);
+        // Without a check for the child after spawning,
+        // the attempt would wait for the check to finish.
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
 }
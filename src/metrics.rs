@@ -0,0 +1,104 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writing `--metrics-file` in the format read by
+//! the node_exporter textfile collector.
+//!
+//! Metrics are best-effort: a file that can't be written is logged and skipped.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::log;
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+static CHECK_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static CHECK_FAILURES: AtomicU64 = AtomicU64::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+/// Whether a failure to write the file has been logged as a warning.
+/// Later failures are logged at debug level so they don't flood the log.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Start writing metrics to `path`, beginning with zeroed counters.
+pub(crate) fn set_path(path: PathBuf) {
+    if PATH.set(path).is_ok() {
+        write();
+    }
+}
+
+/// Count a health check attempt that passed or failed.
+pub(crate) fn record_check(passed: bool) {
+    CHECK_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    if !passed {
+        CHECK_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+    write();
+}
+
+/// Set whether the child program is currently considered ready.
+pub(crate) fn set_ready(ready: bool) {
+    if READY.swap(ready, Ordering::Relaxed) != ready {
+        write();
+    }
+}
+
+/// Rewrite the metrics file, if any.
+fn write() {
+    let Some(path) = PATH.get() else {
+        return;
+    };
+    let mut text = String::new();
+    let metrics = [
+        (
+            "health_notify_check_attempts_total",
+            "counter",
+            "Health check attempts that completed.",
+            CHECK_ATTEMPTS.load(Ordering::Relaxed),
+        ),
+        (
+            "health_notify_check_failures_total",
+            "counter",
+            "Health check attempts that failed.",
+            CHECK_FAILURES.load(Ordering::Relaxed),
+        ),
+        (
+            "health_notify_ready",
+            "gauge",
+            "Whether the child program is ready (1) or not (0).",
+            u64::from(READY.load(Ordering::Relaxed)),
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+    if let Err(e) = crate::replace_file(path, text.as_bytes()) {
+        let level = if WARNED.swap(true, Ordering::Relaxed) {
+            log::Level::Debug
+        } else {
+            log::Level::Warn
+        };
+        log::log(
+            level,
+            "metrics",
+            &[("error", e.to_string().as_str().into())],
+            format_args!("writing --metrics-file {}: {}", path.display(), e),
+        );
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use nix::libc::{self, pid_t};
+use nix::unistd::{getpgid, Pid};
+
+use crate::log;
+use crate::sd_notify::{self, SystemdNotify};
+use crate::DisplaySender;
+
+/// Maximum number of file descriptors in one message (the kernel's `SCM_MAX_FD`).
+const MAX_FDS: usize = 253;
+
+/// `NotifyProxy` is a notify socket for the child program
+/// that relays the child's notifications to the service manager,
+/// so that health-notify can observe them.
+///
+/// The socket is in the abstract namespace,
+/// so it needs no cleanup and is reachable regardless of the child's user.
+/// Since any local process can reach it,
+/// only messages sent from the child program's process group are relayed.
+#[derive(Debug)]
+pub(crate) struct NotifyProxy {
+    address: OsString,
+    shared: Arc<Shared>,
+    child: mpsc::Sender<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// Whether the child program has sent any notification.
+    first_message: AtomicBool,
+    /// Whether the child program has sent `READY=1`.
+    child_ready: AtomicBool,
+    /// Whether health-notify has sent `READY=1` itself.
+    /// Until then, the child's `READY=1` is held back.
+    started: AtomicBool,
+    /// Whether a `--reload-on-hup` reload is waiting for the child's `READY=1`.
+    reloading: AtomicBool,
+}
+
+impl NotifyProxy {
+    /// Bind the proxy socket and start relaying messages to `upstream` on a new thread.
+    /// Messages wait in the socket until [`set_child`](Self::set_child) is called.
+    /// The returned stream is written to when the child program sends its first notification
+    /// and when it first sends `READY=1`.
+    pub(crate) fn start(upstream: Option<SystemdNotify>) -> io::Result<(Self, UnixStream)> {
+        let socket = UnixDatagram::unbound()?;
+        autobind(&socket)?;
+        let mut address = OsString::from("@");
+        address.push(OsStr::from_bytes(
+            socket.local_addr()?.as_abstract_name().unwrap_or_default(),
+        ));
+        sd_notify::set_pass_cred(&socket)?;
+        let (wakeup_read, wakeup_write) = UnixStream::pair()?;
+        let (child_tx, child_rx) = mpsc::channel();
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("notify-proxy".to_string())
+            .spawn(move || {
+                // Without a child program, there's nobody to relay for.
+                let Ok(child) = child_rx.recv() else {
+                    return;
+                };
+                relay(socket, upstream, child, &thread_shared, wakeup_write)
+            })?;
+        let proxy = NotifyProxy {
+            address,
+            shared,
+            child: child_tx,
+        };
+        Ok((proxy, wakeup_read))
+    }
+
+    /// Start relaying messages sent by the process `pid`
+    /// or by other members of its process group.
+    pub(crate) fn set_child(&self, pid: u32) {
+        let _ = self.child.send(pid);
+    }
+
+    /// Returns the value of `NOTIFY_SOCKET` that refers to the proxy.
+    pub(crate) fn address(&self) -> &OsStr {
+        &self.address
+    }
+
+    /// Reports whether the child program has sent any notification.
+    pub(crate) fn first_message(&self) -> bool {
+        self.shared.first_message.load(Ordering::Acquire)
+    }
+
+    /// Reports whether the child program has sent `READY=1`.
+    pub(crate) fn child_ready(&self) -> bool {
+        self.shared.child_ready.load(Ordering::Acquire)
+    }
+
+    /// Pass later `READY=1` messages from the child program through to the service manager,
+    /// as after a reload.
+    /// Call this once health-notify has sent `READY=1` itself.
+    pub(crate) fn set_started(&self) {
+        self.shared.started.store(true, Ordering::Release);
+    }
+
+    /// Note that health-notify has sent `RELOADING=1`,
+    /// so that the child program's next `READY=1` is logged as completing the reload.
+    /// The message is relayed to the service manager as usual.
+    pub(crate) fn start_reload(&self) {
+        self.shared.reloading.store(true, Ordering::Release);
+    }
+}
+
+/// Bind `socket` to a unique name in the abstract namespace chosen by the kernel,
+/// so that proxies never collide, even across PID namespaces.
+fn autobind(socket: &UnixDatagram) -> io::Result<()> {
+    // SAFETY: sockaddr_un is a plain C struct for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // SAFETY: addr is valid for the given length,
+    // which covers only the address family, asking the kernel to pick a name.
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sa_family_t>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive messages from the child program and send them to `upstream`,
+/// along with any file descriptors they carry (as for `FDSTORE=1`).
+/// Messages from processes outside of `child`'s process group are dropped,
+/// as are messages too long for the service manager.
+/// Runs until the socket fails.
+fn relay(
+    socket: UnixDatagram,
+    upstream: Option<SystemdNotify>,
+    child: u32,
+    shared: &Shared,
+    mut wakeup: UnixStream,
+) {
+    let child = Pid::from_raw(child as pid_t);
+    // Spawning returns after the child program's exec, so its process group is settled.
+    let group = getpgid(Some(child)).ok();
+    let mut buf = vec![0u8; sd_notify::MAX_MESSAGE_LEN];
+    loop {
+        let Received {
+            len: n,
+            fds,
+            sender,
+            truncated,
+            fds_truncated,
+        } = match recv_with_fds(&socket, &mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::warning!(
+                    "notify_proxy",
+                    error = e.to_string().as_str();
+                    "receiving from notify proxy socket: {}",
+                    e
+                );
+                return;
+            }
+        };
+        // Dropping a message also closes the descriptors it carried,
+        // so they never reach the service manager's file descriptor store.
+        if !sender.is_some_and(|pid| is_child_process(Pid::from_raw(pid), child, group)) {
+            log::warning!(
+                "notify_reject",
+                sender_pid = sender,
+                fds = fds.len() as u32;
+                "dropping notification{} (outside the child program's process group)",
+                DisplaySender(sender)
+            );
+            continue;
+        }
+        if fds_truncated {
+            // Storing only some of the descriptors would be worse than storing none.
+            log::warning!(
+                "notify_reject",
+                sender_pid = sender,
+                fds = fds.len() as u32;
+                "dropping child notification with more than {} file descriptors",
+                MAX_FDS
+            );
+            continue;
+        }
+        if truncated {
+            log::warning!(
+                "notify_reject",
+                sender_pid = sender;
+                "dropping child notification longer than {} bytes",
+                buf.len()
+            );
+            continue;
+        }
+        if !shared.first_message.swap(true, Ordering::AcqRel) {
+            log::debug!("child_first_notify"; "child program sent its first notification");
+            let _ = wakeup.write_all(&[0]);
+        }
+        let mut relayed = Vec::with_capacity(n);
+        for line in buf[..n].split(|&b| b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            if line == b"READY=1" && !shared.started.load(Ordering::Acquire) {
+                // health-notify sends READY=1 once startup is complete.
+                if !shared.child_ready.swap(true, Ordering::AcqRel) {
+                    log::info!("child_ready"; "child program sent READY=1");
+                    let _ = wakeup.write_all(&[0]);
+                }
+                continue;
+            }
+            if line == b"READY=1" && shared.reloading.swap(false, Ordering::AcqRel) {
+                log::info!("reloaded"; "child program sent READY=1 after reload");
+            }
+            if !relayed.is_empty() {
+                relayed.push(b'\n');
+            }
+            relayed.extend_from_slice(line);
+        }
+        if relayed.is_empty() {
+            continue;
+        }
+        log::debug!(
+            "notify_relay",
+            message = String::from_utf8_lossy(&relayed).as_ref(),
+            fds = fds.len() as u32;
+            "relaying child notification: {}",
+            String::from_utf8_lossy(&relayed).replace('\n', " ")
+        );
+        let Some(upstream) = &upstream else {
+            continue;
+        };
+        let raw_fds = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        // Our copies of the descriptors are closed when fds is dropped.
+        if let Err(e) = upstream.notify_with_fds(&relayed, &raw_fds) {
+            log::warning!(
+                "notify_proxy",
+                error = e.to_string().as_str();
+                "relaying child notification: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Reports whether `pid` is the child program or a member of its process `group`.
+fn is_child_process(pid: Pid, child: Pid, group: Option<Pid>) -> bool {
+    if pid == child {
+        return true;
+    }
+    // The sender may have exited since, in which case it can't be vouched for.
+    group.is_some_and(|group| getpgid(Some(pid)) == Ok(group))
+}
+
+/// A datagram received on the proxy socket.
+struct Received {
+    len: usize,
+    /// Descriptors sent with `SCM_RIGHTS`, marked close-on-exec.
+    fds: Vec<OwnedFd>,
+    /// Process ID of the sender, from `SCM_CREDENTIALS`.
+    sender: Option<pid_t>,
+    /// Whether the datagram was longer than the buffer and was cut off.
+    truncated: bool,
+    /// Whether some control data didn't fit (`MSG_CTRUNC`),
+    /// in which case the kernel closed the descriptors that were left out.
+    fds_truncated: bool,
+}
+
+/// Receive a datagram into `buf`,
+/// along with any file descriptors sent with `SCM_RIGHTS`
+/// and the sender's credentials (which requires `SO_PASSCRED` on `socket`).
+fn recv_with_fds(socket: &UnixDatagram, buf: &mut [u8]) -> io::Result<Received> {
+    // SAFETY: CMSG_SPACE only performs arithmetic.
+    let space = unsafe {
+        libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32)
+            + libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as u32)
+    };
+    // Use a u64 buffer so that the control message headers are suitably aligned.
+    let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: msghdr is a plain C struct for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    // SAFETY: The iovec and control buffer outlive the call
+    // and their lengths match their allocations.
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fds = Vec::new();
+    let mut sender = None;
+    // SAFETY: recvmsg filled in the control buffer and msg_controllen,
+    // so the CMSG_* macros only visit complete headers within it.
+    // Each SCM_RIGHTS descriptor is newly installed in this process and owned by us.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let data_len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS
+            {
+                let cred = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+                // Senders in another PID namespace are reported as 0.
+                sender = Some(cred.pid).filter(|&pid| pid > 0);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(Received {
+        len: n as usize,
+        fds,
+        sender,
+        truncated: msg.msg_flags & libc::MSG_TRUNC != 0,
+        fds_truncated: msg.msg_flags & libc::MSG_CTRUNC != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::net::SocketAddr;
+    use std::os::unix::process::CommandExt;
+    use std::process::{self, Command};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Returns the socket address that `NOTIFY_SOCKET` refers to for `proxy`.
+    fn address(proxy: &NotifyProxy) -> SocketAddr {
+        let name = proxy
+            .address()
+            .as_encoded_bytes()
+            .strip_prefix(b"@")
+            .unwrap();
+        SocketAddr::from_abstract_name(name).unwrap()
+    }
+
+    /// Send `msg` to `addr` from this process.
+    fn send(addr: &SocketAddr, msg: &str) {
+        UnixDatagram::unbound()
+            .unwrap()
+            .send_to_addr(msg.as_bytes(), addr)
+            .unwrap();
+    }
+
+    fn recv(upstream: &UnixDatagram) -> String {
+        let mut buf = vec![0u8; sd_notify::MAX_MESSAGE_LEN];
+        let n = upstream.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn relays_child_notifications() {
+        let path = env::temp_dir().join(format!("health-notify-{}-proxy", process::id()));
+        let _ = fs::remove_file(&path);
+        let upstream = UnixDatagram::bind(&path).unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (proxy, mut wakeup) =
+            NotifyProxy::start(Some(SystemdNotify::from_path(&path))).unwrap();
+        // This process stands in for the child program.
+        proxy.set_child(process::id());
+
+        // A process in another process group sends from the socket it opens before exec.
+        let addr = address(&proxy);
+        let mut outsider = Command::new("true");
+        outsider.process_group(0);
+        let outsider_addr = addr.clone();
+        // SAFETY: The closure only makes the socket(2) and sendto(2) system calls.
+        unsafe {
+            outsider.pre_exec(move || {
+                UnixDatagram::unbound()?.send_to_addr(b"STATUS=outsider", &outsider_addr)?;
+                Ok(())
+            });
+        }
+        assert!(outsider.status().unwrap().success());
+        send(&addr, "STATUS=starting");
+        assert_eq!(recv(&upstream), "STATUS=starting");
+        assert!(proxy.first_message());
+        let mut woken = [0u8; 1];
+        wakeup.read_exact(&mut woken).unwrap();
+
+        // READY=1 is held back until health-notify has started,
+        // but the rest of the message goes through.
+        assert!(!proxy.child_ready());
+        send(&addr, "READY=1\nSTATUS=ready");
+        assert_eq!(recv(&upstream), "STATUS=ready");
+        assert!(proxy.child_ready());
+        wakeup.read_exact(&mut woken).unwrap();
+        send(&addr, "READY=1");
+        send(&addr, "WATCHDOG=1");
+        assert_eq!(recv(&upstream), "WATCHDOG=1");
+
+        proxy.set_started();
+        send(&addr, "READY=1");
+        assert_eq!(recv(&upstream), "READY=1");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ChildStdout;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::log;
+
+/// How long [`ReadyMarker::drain`] waits for the rest of the child's output.
+/// Background processes started by the child may keep the pipe open indefinitely.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `ReadyMarker` watches the child program's standard output
+/// for a line that matches `--ready-regex`.
+/// Every line is copied to health-notify's standard output,
+/// before and after the match, so the child never blocks on a full pipe.
+#[derive(Debug)]
+pub(crate) struct ReadyMarker {
+    found: Arc<AtomicBool>,
+    /// Receives a message once the child's output has been copied to the end.
+    done: mpsc::Receiver<()>,
+}
+
+impl ReadyMarker {
+    /// Start copying `stdout` on a new thread.
+    /// The returned stream is written to when a matching line is found.
+    pub(crate) fn start(stdout: ChildStdout, pattern: Regex) -> io::Result<(Self, UnixStream)> {
+        let (wakeup_read, wakeup_write) = UnixStream::pair()?;
+        let found = Arc::new(AtomicBool::new(false));
+        let thread_found = Arc::clone(&found);
+        let (done_tx, done) = mpsc::channel();
+        thread::Builder::new()
+            .name("ready-marker".to_string())
+            .spawn(move || {
+                let mut out = io::stdout();
+                copy_lines(stdout, &pattern, &thread_found, wakeup_write, &mut out);
+                let _ = out.flush();
+                let _ = done_tx.send(());
+            })?;
+        Ok((ReadyMarker { found, done }, wakeup_read))
+    }
+
+    /// Reports whether the child program has printed a matching line.
+    pub(crate) fn found(&self) -> bool {
+        self.found.load(Ordering::Acquire)
+    }
+
+    /// Wait briefly for the rest of the child program's output to be copied.
+    /// Call this after the child program exits and before health-notify does,
+    /// so that output still in the pipe isn't lost.
+    pub(crate) fn drain(&self) {
+        let _ = self.done.recv_timeout(DRAIN_TIMEOUT);
+    }
+}
+
+/// Copy lines from `stdout` to `out` until the child closes it,
+/// setting `found` at the first line that matches `pattern`.
+fn copy_lines(
+    stdout: ChildStdout,
+    pattern: &Regex,
+    found: &AtomicBool,
+    mut wakeup: UnixStream,
+    out: &mut impl Write,
+) {
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::warning!(
+                    "ready_marker",
+                    error = e.to_string().as_str();
+                    "reading child program's output: {}",
+                    e
+                );
+                return;
+            }
+        }
+        // Keep draining even if our own output is gone.
+        let _ = out.write_all(&line);
+        if found.load(Ordering::Relaxed) {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&line);
+        if pattern.is_match(text.trim_end_matches(['\n', '\r'])) {
+            found.store(true, Ordering::Release);
+            log::info!(
+                "ready_marker",
+                pattern = pattern.to_string().as_str();
+                "child program printed a line matching {}",
+                pattern
+            );
+            let _ = wakeup.write_all(&[0]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    #[test]
+    fn copies_output_after_ready_line() {
+        // Far more than a pipe holds, so the child blocks unless we keep reading.
+        let mut child = Command::new("sh")
+            .args([
+                "-c",
+                "echo starting; echo Server started; yes | head -n 200000",
+            ])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let pattern = Regex::new("^Server started$").unwrap();
+        let found = AtomicBool::new(false);
+        let (mut wakeup_read, wakeup_write) = UnixStream::pair().unwrap();
+        let mut out = Vec::new();
+        copy_lines(stdout, &pattern, &found, wakeup_write, &mut out);
+
+        assert!(found.load(Ordering::Acquire));
+        let mut woken = Vec::new();
+        wakeup_read.read_to_end(&mut woken).unwrap();
+        assert_eq!(woken, [0]);
+        assert!(child.wait().unwrap().success());
+        assert!(out.starts_with(b"starting\nServer started\ny\n"));
+        assert_eq!(out.len(), "starting\nServer started\n".len() + 200000 * 2);
+    }
+}
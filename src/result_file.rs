@@ -0,0 +1,50 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writing `--result-file`: a JSON summary of how the run ended.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::log;
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Write the result to `path` when health-notify exits.
+pub(crate) fn set_path(path: PathBuf) {
+    let _ = PATH.set(path);
+}
+
+/// Write the exit summary to the result file, if any,
+/// with the same fields as the `exit` log event.
+/// Failures are logged and otherwise ignored, since health-notify is exiting anyway.
+pub(crate) fn write(fields: &[(&str, log::Value)], message: fmt::Arguments) {
+    let Some(path) = PATH.get() else {
+        return;
+    };
+    let mut json = log::json_object(fields, message);
+    json.push('\n');
+    if let Err(e) = crate::replace_file(path, json.as_bytes()) {
+        log::warning!(
+            "result_file",
+            error = e.to_string().as_str();
+            "writing --result-file {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use nix::libc;
+
+/// A resource that can be limited with a `--limit-*` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resource {
+    Core,
+    Nofile,
+    Nproc,
+}
+
+impl Resource {
+    /// Returns the name of the option that sets this limit.
+    pub(crate) fn option_name(self) -> &'static str {
+        match self {
+            Resource::Core => "--limit-core",
+            Resource::Nofile => "--limit-nofile",
+            Resource::Nproc => "--limit-nproc",
+        }
+    }
+
+    fn getrlimit(self) -> io::Result<Limit> {
+        let mut raw = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: raw is a valid rlimit for getrlimit(2) to fill in.
+        let result = unsafe {
+            match self {
+                Resource::Core => libc::getrlimit(libc::RLIMIT_CORE, &mut raw),
+                Resource::Nofile => libc::getrlimit(libc::RLIMIT_NOFILE, &mut raw),
+                Resource::Nproc => libc::getrlimit(libc::RLIMIT_NPROC, &mut raw),
+            }
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Limit {
+            soft: raw.rlim_cur,
+            hard: raw.rlim_max,
+        })
+    }
+
+    /// Set the limit for the calling process.
+    /// This runs between fork and exec, so it must not allocate.
+    fn setrlimit(self, limit: Limit) -> io::Result<()> {
+        let raw = libc::rlimit {
+            rlim_cur: limit.soft,
+            rlim_max: limit.hard,
+        };
+        // SAFETY: raw is a valid rlimit.
+        let result = unsafe {
+            match self {
+                Resource::Core => libc::setrlimit(libc::RLIMIT_CORE, &raw),
+                Resource::Nofile => libc::setrlimit(libc::RLIMIT_NOFILE, &raw),
+                Resource::Nproc => libc::setrlimit(libc::RLIMIT_NPROC, &raw),
+            }
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A soft and hard limit pair, as given to a `--limit-*` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Limit {
+    soft: libc::rlim_t,
+    hard: libc::rlim_t,
+}
+
+impl Limit {
+    /// Parse `SOFT[:HARD]`, where either value may be `unlimited` (or `infinity`).
+    /// A single value sets both limits.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let (soft, hard) = s.split_once(':').unwrap_or((s, s));
+        let limit = Limit {
+            soft: parse_value(soft)?,
+            hard: parse_value(hard)?,
+        };
+        // RLIM_INFINITY is the largest rlim_t, so it compares correctly.
+        if limit.soft > limit.hard {
+            return Err(format!("soft limit {} is above hard limit {}", soft, hard));
+        }
+        Ok(limit)
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", DisplayValue(self.soft), DisplayValue(self.hard))
+    }
+}
+
+fn parse_value(s: &str) -> Result<libc::rlim_t, String> {
+    if s == "unlimited" || s == "infinity" {
+        return Ok(libc::RLIM_INFINITY);
+    }
+    s.parse::<libc::rlim_t>()
+        .ok()
+        .filter(|&n| n != libc::RLIM_INFINITY)
+        .ok_or_else(|| format!("{:?} is not a number or \"unlimited\"", s))
+}
+
+struct DisplayValue(libc::rlim_t);
+
+impl fmt::Display for DisplayValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == libc::RLIM_INFINITY {
+            f.write_str("unlimited")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Verify that a process running as `root` (or not)
+/// will be allowed to set `limit` for `resource`.
+/// Only privileged processes can raise a hard limit.
+pub(crate) fn check(resource: Resource, limit: Limit, root: bool) -> Result<(), String> {
+    let current = resource
+        .getrlimit()
+        .map_err(|e| format!("{}: {}", resource.option_name(), e))?;
+    if !root && limit.hard > current.hard {
+        return Err(format!(
+            "{} {}: hard limit is above the current hard limit {} \
+             and the child program won't run as root",
+            resource.option_name(),
+            limit,
+            DisplayValue(current.hard),
+        ));
+    }
+    Ok(())
+}
+
+/// Configure `command` to set the given limits before exec.
+/// If any limit can't be set, spawning fails.
+///
+/// This runs after any `uid`/`gid` set on the command have taken effect,
+/// so raising a hard limit requires the new user to be privileged.
+pub(crate) fn apply_on_exec(command: &mut Command, limits: &[(Resource, Limit)]) {
+    let limits = limits.to_vec();
+    unsafe {
+        command.pre_exec(move || {
+            for &(resource, limit) in &limits {
+                resource.setrlimit(limit)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limit() {
+        assert_eq!(
+            Limit::parse("1024"),
+            Ok(Limit {
+                soft: 1024,
+                hard: 1024
+            })
+        );
+        assert_eq!(Limit::parse("0"), Ok(Limit { soft: 0, hard: 0 }));
+        assert_eq!(
+            Limit::parse("1024:4096"),
+            Ok(Limit {
+                soft: 1024,
+                hard: 4096
+            })
+        );
+        assert_eq!(
+            Limit::parse("unlimited"),
+            Ok(Limit {
+                soft: libc::RLIM_INFINITY,
+                hard: libc::RLIM_INFINITY,
+            })
+        );
+        assert_eq!(
+            Limit::parse("1024:infinity"),
+            Ok(Limit {
+                soft: 1024,
+                hard: libc::RLIM_INFINITY,
+            })
+        );
+        assert_eq!(
+            Limit::parse("1024:unlimited").unwrap().to_string(),
+            "1024:unlimited"
+        );
+        assert_eq!(Limit::parse("7").unwrap().to_string(), "7:7");
+    }
+
+    #[test]
+    fn parse_limit_errors() {
+        for arg in [
+            "",
+            "lots",
+            "-1",
+            "1024:",
+            ":1024",
+            "1:2:3",
+            "1.5",
+            &libc::RLIM_INFINITY.to_string(),
+        ] {
+            assert!(
+                Limit::parse(arg).is_err(),
+                "Limit::parse({:?}) succeeded",
+                arg
+            );
+        }
+
+        let err = Limit::parse("4096:1024").unwrap_err();
+        assert!(err.contains("above hard limit"), "{}", err);
+        assert!(Limit::parse("unlimited:1024").is_err());
+    }
+}
@@ -14,32 +14,103 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::borrow::Cow;
 use std::env;
+use std::ffi::OsString;
 use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::net::UnixDatagram;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::time::{clock_gettime, ClockId};
+use nix::unistd::pipe2;
 
 use crate::lazy_fail_init::LazyFailInit;
 
 pub(crate) const ENV_VAR: &str = "NOTIFY_SOCKET";
 
+/// Name in `LISTEN_FDNAMES` of an inherited notify socket.
+const LISTEN_FD_NAME: &str = "notify";
+
+/// First file descriptor passed by the socket activation protocol.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Maximum length of a message that the service manager will read.
+/// systemd reads at most `PIPE_BUF` bytes and ignores longer messages.
+pub(crate) const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Marker appended to a truncated `STATUS=`.
+const ELLIPSIS: &[u8] = b"...";
+
+/// `SystemdNotify` sends notifications to the service manager.
+///
+/// Sends take `&self`, so one handle can be shared between threads.
+/// The socket is connected once and then used without locking;
+/// reconnecting after a stale socket is serialized by an internal lock,
+/// and the socket is reconnected in place so that concurrent sends never see it replaced.
 #[derive(Debug)]
 pub(crate) struct SystemdNotify {
-    socket_path: PathBuf,
+    /// Path of the manager's socket.
+    /// `None` if the socket was inherited and its peer has no path,
+    /// in which case the socket can't be reconnected.
+    socket_path: Option<PathBuf>,
     socket: LazyFailInit<UnixDatagram>,
+    /// Held while reconnecting `socket`.
+    reconnect: Mutex<()>,
+    /// Number of sends that have failed since the last successful one.
+    failures: AtomicU32,
+    /// Whether any send has succeeded.
+    sent: AtomicBool,
 }
 
 impl SystemdNotify {
+    /// Find the notify socket from the environment,
+    /// removing the variables that describe it.
+    /// An already-connected socket passed with `LISTEN_FDS`
+    /// and named `notify` in `LISTEN_FDNAMES` takes precedence over `NOTIFY_SOCKET`.
     pub(crate) fn from_env() -> Option<Self> {
         let socket_path = env::var_os(ENV_VAR).unwrap_or_default();
         env::remove_var(ENV_VAR);
+        if let Some(socket) = take_listen_fd() {
+            let socket_path = socket
+                .peer_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+            return Some(SystemdNotify {
+                socket_path,
+                socket: LazyFailInit::with_value(socket),
+                reconnect: Mutex::new(()),
+                failures: AtomicU32::new(0),
+                sent: AtomicBool::new(false),
+            });
+        }
         if socket_path.is_empty() {
             None
         } else {
-            Some(SystemdNotify {
-                socket_path: socket_path.into(),
-                socket: LazyFailInit::new(),
-            })
+            Some(Self::from_path(absolute_socket_path(socket_path)))
+        }
+    }
+
+    /// Create a notifier for the manager's socket at `path`
+    /// without reading or modifying the environment.
+    /// The socket is connected on the first send.
+    pub(crate) fn from_path(path: impl Into<PathBuf>) -> Self {
+        SystemdNotify {
+            socket_path: Some(path.into()),
+            socket: LazyFailInit::new(),
+            reconnect: Mutex::new(()),
+            failures: AtomicU32::new(0),
+            sent: AtomicBool::new(false),
         }
     }
 
@@ -49,10 +120,545 @@ impl SystemdNotify {
         sd_notify
     }
 
+    pub(crate) fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
+    /// Open another handle to the same service manager socket,
+    /// so that notifications can be sent from another thread.
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        let socket = match (&self.socket_path, self.socket.get()) {
+            // An inherited socket without a path can't be reconnected,
+            // so share it instead.
+            (None, Some(socket)) => LazyFailInit::with_value(socket.try_clone()?),
+            _ => LazyFailInit::new(),
+        };
+        Ok(SystemdNotify {
+            socket_path: self.socket_path.clone(),
+            socket,
+            reconnect: Mutex::new(()),
+            failures: AtomicU32::new(0),
+            sent: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the number of sends that have failed in a row.
+    /// Because the connection is retried on every send,
+    /// this drops back to zero once the manager's socket works again.
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Reports whether any notification has reached the service manager.
+    pub(crate) fn has_sent(&self) -> bool {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Send a notification message to the service manager.
+    /// If the send fails because the manager's socket went away
+    /// (for example, because the manager restarted),
+    /// the socket is reconnected and the send is retried once.
     pub(crate) fn notify(&self, buf: impl AsRef<[u8]>) -> io::Result<()> {
-        let socket = self
-            .socket
-            .get_or_create(|| UnixDatagram::bind(&self.socket_path))?;
-        socket.send(buf.as_ref()).map(|_| ())
+        self.notify_with_fds(buf, &[])
+    }
+
+    /// Send a notification message to the service manager
+    /// along with copies of the given file descriptors,
+    /// as used by the `FDSTORE=1` protocol.
+    /// Failed sends are retried like [`notify`](Self::notify).
+    /// Messages longer than the manager will read have their `STATUS=` truncated to fit,
+    /// or are rejected with [`io::ErrorKind::InvalidInput`] if that isn't enough.
+    pub(crate) fn notify_with_fds(&self, buf: impl AsRef<[u8]>, fds: &[RawFd]) -> io::Result<()> {
+        let buf = fit_message(buf.as_ref())?;
+        let buf = buf.as_ref();
+        let result = match self.send(buf, fds) {
+            Err(e) if is_stale_socket_error(&e) => match self.reconnect() {
+                Ok(true) => self.send(buf, fds),
+                Ok(false) => Err(e),
+                Err(e) => Err(e),
+            },
+            result => result,
+        };
+        if result.is_ok() {
+            self.failures.store(0, Ordering::Relaxed);
+            self.sent.store(true, Ordering::Relaxed);
+        } else {
+            let _ = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    Some(n.saturating_add(1))
+                });
+        }
+        result
+    }
+
+    /// Send several assignments to the service manager in a single message,
+    /// so that the manager applies them together.
+    pub(crate) fn notify_all(&self, entries: &[&str]) -> io::Result<()> {
+        self.notify(entries.join("\n"))
+    }
+
+    /// Ask the service manager to close and forget the file descriptors
+    /// stored under `name` with `FDSTORE=1`.
+    pub(crate) fn fd_store_remove(&self, name: &str) -> io::Result<()> {
+        self.notify_all(&["FDSTOREREMOVE=1", &format!("FDNAME={}", name)])
+    }
+
+    /// Send `BARRIER=1` and wait until the service manager
+    /// has processed all previously sent notifications,
+    /// or until `timeout` elapses.
+    pub(crate) fn barrier(&self, timeout: Duration) -> io::Result<()> {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC)?;
+        self.notify_with_fds("BARRIER=1", &[write_end.as_raw_fd()])?;
+        // The manager signals completion by closing its copy of the write end,
+        // so ours must be closed for the read end to reach EOF.
+        drop(write_end);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fds = [PollFd::new(read_end.as_fd(), PollFlags::POLLIN)];
+            match poll(
+                &mut fds,
+                PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX),
+            ) {
+                Ok(0) => return Err(io::ErrorKind::TimedOut.into()),
+                Ok(_) => return Ok(()),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Connect the socket to the manager's socket path again,
+    /// for example because the manager restarted and bound a new socket.
+    /// Returns `Ok(false)` if the socket can't be reconnected.
+    /// Sends in other threads may use the socket while it's being reconnected,
+    /// in which case they either reach the new socket or fail and reconnect themselves.
+    fn reconnect(&self) -> io::Result<bool> {
+        let Some(path) = &self.socket_path else {
+            return Ok(false);
+        };
+        let _lock = self
+            .reconnect
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // A datagram socket can be connected again to change its peer.
+        // If it was never connected, the next send connects it for the first time.
+        if let Some(socket) = self.socket.get() {
+            socket.connect(path)?;
+        }
+        Ok(true)
+    }
+
+    fn send(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+        let socket = self.socket.get_or_try_init(|| match &self.socket_path {
+            Some(path) => connect(path),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        })?;
+        if fds.is_empty() {
+            socket.send(buf).map(|_| ())
+        } else {
+            send_with_fds(socket, buf, fds)
+        }
+    }
+}
+
+/// Resolve a relative `NOTIFY_SOCKET` against our working directory.
+/// systemd always passes an absolute path or an abstract address starting with `@`,
+/// but the path is also passed on to the child and health check programs,
+/// which may run in another directory with `--chdir`.
+/// If the working directory can't be found, the path is left as is.
+fn absolute_socket_path(path: OsString) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() || path.as_os_str().as_bytes().starts_with(b"@") {
+        return path;
+    }
+    match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path,
+    }
+}
+
+/// Take ownership of the notify socket passed with the socket activation protocol,
+/// if any, and remove the `LISTEN_*` variables from the environment.
+/// The descriptor is marked close-on-exec so that it isn't leaked to the child program.
+fn take_listen_fd() -> Option<UnixDatagram> {
+    let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let count = env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let index = names.split(':').position(|name| name == LISTEN_FD_NAME)?;
+    let index = RawFd::try_from(index).ok().filter(|&i| i < count)?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fd = LISTEN_FDS_START + index;
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).ok()?;
+    // SAFETY: The socket activation protocol hands ownership of
+    // the descriptors in LISTEN_FDS to this process,
+    // and we've removed the variables so nothing else will claim it.
+    Some(unsafe { UnixDatagram::from_raw_fd(fd) })
+}
+
+/// Open a datagram socket to the manager's notify socket.
+/// `SO_PASSCRED` is enabled so that the manager receives our credentials
+/// even when `NotifyAccess=` requires them.
+fn connect(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    set_pass_cred(&socket)?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Enable `SO_PASSCRED` on `socket`,
+/// so that the sender's credentials accompany every message sent or received on it.
+pub(crate) fn set_pass_cred(socket: &UnixDatagram) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    // SAFETY: The socket is a valid descriptor for the duration of the call
+    // and the option value points to a c_int of the given size.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send `buf` on a connected socket with an `SCM_RIGHTS` control message
+/// carrying `fds`.
+fn send_with_fds(socket: &UnixDatagram, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_size = u32::try_from(mem::size_of_val(fds))
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: CMSG_SPACE and CMSG_LEN only perform arithmetic.
+    let (space, len) = unsafe { (libc::CMSG_SPACE(fds_size), libc::CMSG_LEN(fds_size)) };
+    // Use a u64 buffer so that the control message header is suitably aligned.
+    let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: msghdr is a plain C struct for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    // SAFETY: The control buffer is at least CMSG_SPACE bytes
+    // and aligned for cmsghdr, so the first header and its data fit inside it.
+    // sendmsg only reads from buf through the iovec.
+    let result = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = len as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the value of `FDSTORE_MAX` from the environment, if set:
+/// the number of file descriptors the manager will hold for us.
+pub(crate) fn fd_store_max() -> Option<usize> {
+    env::var("FDSTORE_MAX").ok()?.parse().ok()
+}
+
+/// Shorten `buf` to [`MAX_MESSAGE_LEN`] by truncating its `STATUS=` value, if needed.
+fn fit_message(buf: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if buf.len() <= MAX_MESSAGE_LEN {
+        return Ok(Cow::Borrowed(buf));
+    }
+    let too_long = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "notification is {} bytes (limit is {})",
+                buf.len(),
+                MAX_MESSAGE_LEN
+            ),
+        )
+    };
+    let mut start = 0;
+    let (value_start, value_end) = loop {
+        let end = buf[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(buf.len(), |i| start + i);
+        if buf[start..end].starts_with(b"STATUS=") {
+            break (start + b"STATUS=".len(), end);
+        }
+        if end == buf.len() {
+            return Err(too_long());
+        }
+        start = end + 1;
+    };
+    let excess = buf.len() - MAX_MESSAGE_LEN + ELLIPSIS.len();
+    if value_end - value_start < excess {
+        return Err(too_long());
+    }
+    let mut cut = value_end - excess;
+    // Don't split a UTF-8 sequence.
+    while cut > value_start && buf[cut] & 0xc0 == 0x80 {
+        cut -= 1;
+    }
+    let mut fitted = Vec::with_capacity(MAX_MESSAGE_LEN);
+    fitted.extend_from_slice(&buf[..cut]);
+    fitted.extend_from_slice(ELLIPSIS);
+    fitted.extend_from_slice(&buf[value_end..]);
+    Ok(Cow::Owned(fitted))
+}
+
+/// Reports whether `e` indicates that the manager's socket
+/// is no longer the one we connected to.
+/// Once a send has found the peer gone, the kernel disconnects the socket,
+/// so other sends racing with the reconnect fail with `ENOTCONN`.
+fn is_stale_socket_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound | io::ErrorKind::NotConnected
+    )
+}
+
+/// Returns the current `CLOCK_MONOTONIC` time in microseconds,
+/// as expected by `MONOTONIC_USEC=`.
+pub(crate) fn monotonic_usec() -> u64 {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("CLOCK_MONOTONIC is always available");
+    now.tv_sec() as u64 * 1_000_000 + now.tv_nsec() as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::linux::net::SocketAddrExt;
+    use std::process;
+    use std::ptr;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    /// Returns a path for a test's socket that no other test uses.
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("health-notify-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Receive a message, with room for one byte more than the manager would read.
+    fn recv(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN + 1];
+        let n = socket.recv(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn relative_socket_path_is_resolved() {
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            absolute_socket_path("run/notify".into()),
+            cwd.join("run/notify")
+        );
+        assert_eq!(
+            absolute_socket_path("/run/systemd/notify".into()),
+            Path::new("/run/systemd/notify")
+        );
+        assert_eq!(
+            absolute_socket_path("@abstract/notify".into()),
+            Path::new("@abstract/notify")
+        );
+    }
+
+    #[test]
+    fn notify_reconnects_to_recreated_socket() {
+        let path = socket_path("reconnect");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notify = SystemdNotify::from_path(&path);
+        notify.notify("STATUS=first").unwrap();
+        assert_eq!(recv(&receiver), b"STATUS=first");
+
+        // The manager restarts and binds a new socket at the same path.
+        drop(receiver);
+        fs::remove_file(&path).unwrap();
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify.notify("STATUS=second").unwrap();
+        assert_eq!(recv(&receiver), b"STATUS=second");
+        assert_eq!(notify.failures(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    const SEND_THREADS: usize = 8;
+    const SENDS_PER_THREAD: usize = 100;
+
+    /// Send [`SENDS_PER_THREAD`] messages from each of [`SEND_THREADS`] threads
+    /// sharing `notify`, and check that `receiver` gets every one intact
+    /// and in order for each thread.
+    fn send_concurrently(notify: SystemdNotify, receiver: &UnixDatagram) {
+        let notify = Arc::new(notify);
+        let senders = (0..SEND_THREADS)
+            .map(|t| {
+                let notify = Arc::clone(&notify);
+                thread::spawn(move || {
+                    for i in 0..SENDS_PER_THREAD {
+                        let message = format!("STATUS={}:{}:{}", t, i, "x".repeat(1000));
+                        notify.notify(message).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut next = [0; SEND_THREADS];
+        for _ in 0..SEND_THREADS * SENDS_PER_THREAD {
+            let message = String::from_utf8(recv(receiver)).unwrap();
+            let mut fields = message.strip_prefix("STATUS=").unwrap().split(':');
+            let t = fields.next().unwrap().parse::<usize>().unwrap();
+            let i = fields.next().unwrap().parse::<usize>().unwrap();
+            assert_eq!(
+                fields.next(),
+                Some("x".repeat(1000).as_str()),
+                "{}",
+                message
+            );
+            assert_eq!(i, next[t], "thread {} out of order", t);
+            next[t] += 1;
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(next, [SENDS_PER_THREAD; SEND_THREADS]);
+        assert_eq!(notify.failures(), 0);
+    }
+
+    #[test]
+    fn concurrent_sends_arrive_intact() {
+        let path = socket_path("concurrent");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send_concurrently(SystemdNotify::from_path(&path), &receiver);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_sends_reconnect_to_recreated_socket() {
+        let path = socket_path("concurrent-reconnect");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notify = SystemdNotify::from_path(&path);
+        notify.notify("STATUS=first").unwrap();
+        assert_eq!(recv(&receiver), b"STATUS=first");
+
+        // The manager restarts, so the first of the concurrent sends must reconnect.
+        drop(receiver);
+        fs::remove_file(&path).unwrap();
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send_concurrently(notify, &receiver);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Receive a message on a socket with `SO_PASSCRED` enabled,
+    /// returning the sender's PID from `SCM_CREDENTIALS`.
+    fn recv_sender_pid(socket: &UnixDatagram) -> Option<libc::pid_t> {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        // SAFETY: CMSG_SPACE only performs arithmetic.
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as u32) };
+        let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // SAFETY: msghdr is a plain C struct for which all zeroes is a valid value.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        // SAFETY: The buffers outlive the call and their lengths match their allocations,
+        // and the CMSG_* macros only visit headers that recvmsg filled in.
+        unsafe {
+            assert!(libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) >= 0);
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if cmsg.is_null()
+                || (*cmsg).cmsg_level != libc::SOL_SOCKET
+                || (*cmsg).cmsg_type != libc::SCM_CREDENTIALS
+            {
+                return None;
+            }
+            let cred = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+            Some(cred.pid)
+        }
+    }
+
+    #[test]
+    fn connect_passes_credentials() {
+        let path = socket_path("passcred");
+        // systemd enables SO_PASSCRED on its notify socket.
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        set_pass_cred(&receiver).unwrap();
+        let socket = connect(&path).unwrap();
+        // SO_PASSCRED on an unbound socket gives it an address on its first send,
+        // which is what lets the manager identify the sender.
+        socket.send(b"READY=1").unwrap();
+        assert!(socket.local_addr().unwrap().as_abstract_name().is_some());
+        assert_eq!(
+            recv_sender_pid(&receiver),
+            Some(process::id() as libc::pid_t)
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fit_message_truncates_long_status() {
+        let status = "x".repeat(200 * 1024);
+        let message = format!("READY=1\nSTATUS={}\nMAINPID=42", status);
+        let fitted = fit_message(message.as_bytes()).unwrap();
+        assert!(fitted.len() <= MAX_MESSAGE_LEN, "{} bytes", fitted.len());
+        assert!(fitted.starts_with(b"READY=1\nSTATUS=xxx"));
+        assert!(fitted.ends_with(b"xxx...\nMAINPID=42"));
+    }
+
+    #[test]
+    fn fit_message_keeps_utf8_intact() {
+        let message = format!("STATUS={}", "\u{e9}".repeat(MAX_MESSAGE_LEN));
+        let fitted = fit_message(message.as_bytes()).unwrap();
+        assert!(fitted.len() <= MAX_MESSAGE_LEN);
+        assert!(std::str::from_utf8(&fitted).is_ok());
+    }
+
+    #[test]
+    fn fit_message_rejects_long_message_without_status() {
+        let message = format!("FDNAME={}", "x".repeat(MAX_MESSAGE_LEN));
+        let err = fit_message(message.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn notify_sends_truncated_status() {
+        let path = socket_path("long-status");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notify = SystemdNotify::from_path(&path);
+        let status = "x".repeat(200 * 1024);
+        notify
+            .notify_all(&["READY=1", &format!("STATUS={}", status)])
+            .unwrap();
+        let received = recv(&receiver);
+        assert!(received.len() <= MAX_MESSAGE_LEN);
+        assert!(received.starts_with(b"READY=1\nSTATUS=xxx"));
+        assert!(received.ends_with(ELLIPSIS));
+
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,86 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use nix::sys::signal::Signal;
+
+/// Other names for signals that `Signal`'s own parser doesn't know,
+/// without the `SIG` prefix.
+const ALIASES: &[(&str, Signal)] = &[
+    ("IOT", Signal::SIGABRT),
+    ("POLL", Signal::SIGIO),
+    ("CLD", Signal::SIGCHLD),
+];
+
+/// Parse a signal given by name or by number,
+/// as for `--kill-signal`, `--forward-signal`, and `--stop-sequence`.
+/// Names are case-insensitive and the `SIG` prefix is optional,
+/// so `SIGTERM`, `TERM`, `term`, and `15` are all the same signal.
+pub(crate) fn parse(s: &str) -> Result<Signal, String> {
+    if let Ok(n) = s.parse::<i32>() {
+        return Signal::try_from(n).map_err(|_| format!("invalid signal number {}", n));
+    }
+    let upper = s.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    if let Some(&(_, signal)) = ALIASES.iter().find(|&&(alias, _)| alias == name) {
+        return Ok(signal);
+    }
+    format!("SIG{}", name)
+        .parse::<Signal>()
+        .map_err(|_| format!("unknown signal {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_signal() {
+        for signal in Signal::iterator() {
+            let name = signal.as_str();
+            let short = name.strip_prefix("SIG").unwrap();
+            assert_eq!(parse(name), Ok(signal), "{}", name);
+            assert_eq!(parse(short), Ok(signal), "{}", short);
+            assert_eq!(parse(&short.to_ascii_lowercase()), Ok(signal), "{}", short);
+            assert_eq!(parse(&(signal as i32).to_string()), Ok(signal), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parses_aliases() {
+        for &(alias, signal) in ALIASES {
+            assert_eq!(parse(alias), Ok(signal), "{}", alias);
+            assert_eq!(parse(&format!("SIG{}", alias)), Ok(signal), "{}", alias);
+            assert_eq!(parse(&alias.to_ascii_lowercase()), Ok(signal), "{}", alias);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_signals() {
+        assert_eq!(parse("0"), Err("invalid signal number 0".to_string()));
+        assert_eq!(parse("-9"), Err("invalid signal number -9".to_string()));
+        assert_eq!(parse("999"), Err("invalid signal number 999".to_string()));
+        assert_eq!(parse(""), Err("unknown signal \"\"".to_string()));
+        assert_eq!(parse("SIG"), Err("unknown signal \"SIG\"".to_string()));
+        assert_eq!(
+            parse("SIGFOO"),
+            Err("unknown signal \"SIGFOO\"".to_string())
+        );
+        assert_eq!(
+            parse("SIGSIGTERM"),
+            Err("unknown signal \"SIGSIGTERM\"".to_string())
+        );
+    }
+}
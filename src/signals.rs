@@ -0,0 +1,126 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::borrow::Borrow;
+use std::io::{self, Read};
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use signal_hook::iterator::backend::{Pending, SignalDelivery};
+use signal_hook::iterator::exfiltrator::WithOrigin;
+
+/// `Signals` delivers signals through a self-pipe
+/// that can be waited on with a timeout,
+/// so that timed waits and signal handling share one path.
+#[derive(Debug)]
+pub(crate) struct Signals {
+    delivery: SignalDelivery<UnixStream, WithOrigin>,
+    /// Stream from another thread that wakes up waits when written to.
+    wakeup: Option<UnixStream>,
+}
+
+impl Signals {
+    /// Register handlers for the given signals.
+    pub(crate) fn new<I, S>(signals: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Borrow<c_int>,
+    {
+        let (read, write) = UnixStream::pair()?;
+        let delivery = SignalDelivery::with_pipe(read, write, WithOrigin::default(), signals)?;
+        Ok(Signals {
+            delivery,
+            wakeup: None,
+        })
+    }
+
+    /// Also end waits whenever data arrives on `stream`,
+    /// so that other threads can interrupt them.
+    /// Such waits return no signals.
+    pub(crate) fn add_wakeup(&mut self, stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        self.wakeup = Some(stream);
+        Ok(())
+    }
+
+    /// Block until at least one signal arrives, then return the received signals.
+    /// The result may still be empty due to spurious wakeups.
+    pub(crate) fn wait(&mut self) -> Pending<WithOrigin> {
+        self.wait_timeout(None)
+    }
+
+    /// Block until at least one signal arrives or `timeout` elapses,
+    /// then return the received signals.
+    /// `None` waits indefinitely.
+    /// The result is empty if the timeout elapsed
+    /// or the wait was ended by the [wakeup stream](Self::add_wakeup).
+    pub(crate) fn wait_timeout(&mut self, timeout: Option<Duration>) -> Pending<WithOrigin> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX)
+                }
+                None => PollTimeout::NONE,
+            };
+            let mut fds = vec![PollFd::new(
+                self.delivery.get_read().as_fd(),
+                PollFlags::POLLIN,
+            )];
+            if let Some(wakeup) = &self.wakeup {
+                fds.push(PollFd::new(wakeup.as_fd(), PollFlags::POLLIN));
+            }
+            match poll(&mut fds, timeout) {
+                Err(Errno::EINTR) => continue,
+                // Other errors can only come from misuse of the descriptors,
+                // which we own.
+                result => {
+                    result.expect("poll signal pipe");
+                    drop(fds);
+                    self.drain_wakeup();
+                    return self.delivery.pending();
+                }
+            }
+        }
+    }
+
+    /// Discard any data written to the wakeup stream.
+    fn drain_wakeup(&mut self) {
+        let Some(wakeup) = &mut self.wakeup else {
+            return;
+        };
+        let mut buf = [0u8; 64];
+        loop {
+            match wakeup.read(&mut buf) {
+                // The writer is gone, so stop polling the stream:
+                // it would always be readable.
+                Ok(0) => {
+                    self.wakeup = None;
+                    return;
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // WouldBlock means the stream is empty.
+                Err(_) => return,
+            }
+        }
+    }
+}
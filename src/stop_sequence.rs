@@ -0,0 +1,349 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::duration;
+use crate::log;
+use crate::pidfd::PidFd;
+use crate::signal_name;
+
+/// How health-notify stops the child program on its own:
+/// signals to send in turn, each but the last followed by
+/// how long to wait for the child program to exit before sending the next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StopSequence {
+    steps: Vec<(Signal, Duration)>,
+    last: Signal,
+}
+
+impl StopSequence {
+    /// Returns a sequence that sends only `signal`.
+    pub(crate) fn single(signal: Signal) -> Self {
+        StopSequence {
+            steps: Vec::new(),
+            last: signal,
+        }
+    }
+
+    /// Returns a sequence that sends `signal`, then SIGKILL after `timeout`.
+    pub(crate) fn escalating(signal: Signal, timeout: Duration) -> Self {
+        if signal == Signal::SIGKILL {
+            return StopSequence::single(signal);
+        }
+        StopSequence {
+            steps: vec![(signal, timeout)],
+            last: Signal::SIGKILL,
+        }
+    }
+
+    /// Parse a `--stop-sequence` argument like `TERM:10s,INT:5s,KILL`.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let mut steps = Vec::new();
+        let mut parts = s.split(',').peekable();
+        while let Some(part) = parts.next() {
+            let (signal, delay) = match part.split_once(':') {
+                Some((signal, delay)) => (signal, Some(delay)),
+                None => (part, None),
+            };
+            let signal = signal_name::parse(signal.trim())?;
+            match (delay, parts.peek().is_some()) {
+                (Some(delay), true) => steps.push((signal, duration::parse(delay.trim())?)),
+                (None, false) => {
+                    return Ok(StopSequence {
+                        steps,
+                        last: signal,
+                    })
+                }
+                (None, true) => {
+                    return Err(format!(
+                        "{}: missing time to wait before the next signal",
+                        part
+                    ))
+                }
+                (Some(_), false) => {
+                    return Err(format!(
+                        "{}: the last signal can't have a time to wait",
+                        part
+                    ))
+                }
+            }
+        }
+        unreachable!("split always yields at least one part")
+    }
+
+    /// Send each signal to `target` in turn until the process that `pidfd` refers to exits.
+    /// Unless `target` is a process group, signals are sent through `pidfd` when given.
+    /// This returns once the last signal is sent or the process has exited,
+    /// but doesn't reap the process.
+    /// Without a pidfd, the process must be a child of ours that nothing else reaps
+    /// until this returns, and its exit is polled for between signals.
+    pub(crate) fn run(&self, pid: u32, pidfd: Option<&PidFd>, target: Pid) {
+        let signals = self
+            .steps
+            .iter()
+            .map(|&(signal, delay)| (signal, Some(delay)))
+            .chain([(self.last, None)]);
+        let mut waited = None;
+        for (signal, delay) in signals {
+            match waited {
+                None => log::info!(
+                    "child_terminate",
+                    pid = pid,
+                    signal = signal.as_str();
+                    "terminating child with {}",
+                    signal
+                ),
+                Some(waited) => log::info!(
+                    "child_terminate",
+                    pid = pid,
+                    signal = signal.as_str();
+                    "child still running after {:?}; sending {}",
+                    waited,
+                    signal
+                ),
+            }
+            match pidfd {
+                // The main thread may reap the child at any time,
+                // so signal through the pidfd to avoid reaching a reused PID.
+                // A process group can only be signalled by its ID.
+                Some(pidfd) if target.as_raw() > 0 => {
+                    let _ = pidfd.send_signal(signal);
+                }
+                _ => {
+                    let _ = kill(target, signal);
+                }
+            }
+            let Some(delay) = delay else {
+                return;
+            };
+            let exited = match pidfd {
+                Some(pidfd) => pidfd.wait_exit(Some(delay)),
+                None => poll_child_exit(pid, delay),
+            };
+            if exited {
+                return;
+            }
+            waited = Some(delay);
+        }
+    }
+}
+
+/// How often [`poll_child_exit`] checks whether the child has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wait up to `timeout` for our child process `pid` to exit, without reaping it.
+/// Reports whether it exited.
+fn poll_child_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // SAFETY: info is a valid siginfo_t for waitid(2) to fill in.
+        // WNOWAIT leaves the child waitable for whoever reaps it.
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        };
+        if result < 0 {
+            if Errno::last() == Errno::EINTR {
+                continue;
+            }
+            // The process isn't our child or is already reaped.
+            return true;
+        }
+        // With WNOHANG, si_pid stays zero until the child has exited.
+        if unsafe { info.si_pid() } != 0 {
+            return true;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        thread::sleep(remaining.min(POLL_INTERVAL));
+    }
+}
+
+impl fmt::Display for StopSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (signal, delay) in &self.steps {
+            write!(f, "{}:{:?},", signal, delay)?;
+        }
+        write!(f, "{}", self.last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    #[test]
+    fn parses_sequences() {
+        assert_eq!(
+            StopSequence::parse("KILL"),
+            Ok(StopSequence::single(Signal::SIGKILL))
+        );
+        assert_eq!(
+            StopSequence::parse("TERM:10s,INT:500ms,KILL"),
+            Ok(StopSequence {
+                steps: vec![
+                    (Signal::SIGTERM, Duration::from_secs(10)),
+                    (Signal::SIGINT, Duration::from_millis(500)),
+                ],
+                last: Signal::SIGKILL,
+            })
+        );
+        assert_eq!(
+            StopSequence::parse(" SIGTERM : 1m , 9 "),
+            Ok(StopSequence {
+                steps: vec![(Signal::SIGTERM, Duration::from_secs(60))],
+                last: Signal::SIGKILL,
+            })
+        );
+        assert_eq!(
+            StopSequence::parse("term:5,kill"),
+            Ok(StopSequence {
+                steps: vec![(Signal::SIGTERM, Duration::from_secs(5))],
+                last: Signal::SIGKILL,
+            })
+        );
+    }
+
+    #[test]
+    fn escalating_ends_with_sigkill() {
+        assert_eq!(
+            StopSequence::escalating(Signal::SIGTERM, Duration::from_secs(10)),
+            StopSequence::parse("TERM:10s,KILL").unwrap()
+        );
+        assert_eq!(
+            StopSequence::escalating(Signal::SIGKILL, Duration::from_secs(10)),
+            StopSequence::single(Signal::SIGKILL)
+        );
+    }
+
+    #[test]
+    fn display_parses_back() {
+        let sequence = StopSequence::parse("TERM:10s,INT:500ms,KILL").unwrap();
+        assert_eq!(sequence.to_string(), "SIGTERM:10s,SIGINT:500ms,SIGKILL");
+        assert_eq!(StopSequence::parse(&sequence.to_string()), Ok(sequence));
+    }
+
+    #[test]
+    fn rejects_invalid_sequences() {
+        assert_eq!(
+            StopSequence::parse("TERM,KILL"),
+            Err("TERM: missing time to wait before the next signal".to_string())
+        );
+        assert_eq!(
+            StopSequence::parse("TERM:10s"),
+            Err("TERM:10s: the last signal can't have a time to wait".to_string())
+        );
+        assert_eq!(
+            StopSequence::parse("TERM:10s,KILL:1s"),
+            Err("KILL:1s: the last signal can't have a time to wait".to_string())
+        );
+        assert_eq!(
+            StopSequence::parse(""),
+            Err("unknown signal \"\"".to_string())
+        );
+        assert_eq!(
+            StopSequence::parse("TERM:10s,"),
+            Err("unknown signal \"\"".to_string())
+        );
+        assert_eq!(
+            StopSequence::parse("BOGUS"),
+            Err("unknown signal \"BOGUS\"".to_string())
+        );
+        assert!(StopSequence::parse("TERM:10x,KILL").is_err());
+        assert!(StopSequence::parse("TERM:,KILL").is_err());
+    }
+
+    #[test]
+    fn run_without_pidfd_escalates() {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; echo ready; exec sleep 60"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "ready\n");
+        let pid = Pid::from_raw(child.id().try_into().unwrap());
+
+        StopSequence::parse("TERM:200ms,KILL")
+            .unwrap()
+            .run(child.id(), None, pid);
+
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGKILL as i32));
+    }
+
+    #[test]
+    fn run_without_pidfd_stops_after_exit() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = Pid::from_raw(child.id().try_into().unwrap());
+
+        let start = Instant::now();
+        StopSequence::parse("TERM:60s,KILL")
+            .unwrap()
+            .run(child.id(), None, pid);
+
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "{:?}",
+            start.elapsed()
+        );
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+    }
+
+    #[test]
+    fn run_signals_through_pidfd() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        // Stand in for a process that reused the reaped child's PID.
+        let mut other = Command::new("sleep").arg("60").spawn().unwrap();
+        let other_pid = Pid::from_raw(other.id() as i32);
+        let other_pidfd = PidFd::open(other.id()).unwrap();
+
+        StopSequence::parse("TERM:10s,KILL")
+            .unwrap()
+            .run(child.id(), Some(&pidfd), other_pid);
+
+        assert!(!other_pidfd.wait_exit(Some(Duration::from_millis(200))));
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2024 Ross Light
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use nix::fcntl::{open, OFlag};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::prctl::set_dumpable;
+use nix::sys::stat::Mode;
+use nix::unistd::{getgid, getuid, write};
+
+/// Configure `command` to run in a new user namespace
+/// where the user and group it would otherwise run as are mapped to root.
+///
+/// This runs after any `uid`/`gid` set on the command have taken effect,
+/// so it composes with `--user` and `--group`.
+pub(crate) fn enter_on_exec(command: &mut Command) {
+    unsafe {
+        command.pre_exec(enter);
+    }
+}
+
+/// Unshare the user namespace and write the ID maps.
+/// This runs between fork and exec, so it must not allocate.
+fn enter() -> io::Result<()> {
+    let uid = getuid().as_raw();
+    let gid = getgid().as_raw();
+    unshare(CloneFlags::CLONE_NEWUSER)?;
+
+    // Switching users clears the dumpable flag,
+    // which makes our /proc/self files owned by root.
+    // exec(2) recomputes the flag, so this doesn't outlive the hook.
+    set_dumpable(true)?;
+
+    // Unprivileged processes must disable setgroups(2)
+    // before they are allowed to write a GID map.
+    write_proc_file("/proc/self/setgroups", b"deny")?;
+    let mut buf = [0u8; 32];
+    write_proc_file("/proc/self/uid_map", format_id_map(&mut buf, uid)?)?;
+    write_proc_file("/proc/self/gid_map", format_id_map(&mut buf, gid)?)?;
+    Ok(())
+}
+
+/// Format a single-line ID map that maps `outside_id` to 0.
+fn format_id_map(buf: &mut [u8], outside_id: u32) -> io::Result<&[u8]> {
+    let mut cursor = &mut buf[..];
+    writeln!(cursor, "0 {} 1", outside_id)?;
+    let n = cursor.len();
+    let len = buf.len() - n;
+    Ok(&buf[..len])
+}
+
+fn write_proc_file(path: &str, data: &[u8]) -> io::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    write(&fd, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_id_map() {
+        let mut buf = [0u8; 32];
+        assert_eq!(format_id_map(&mut buf, 1000).unwrap(), b"0 1000 1\n");
+        assert_eq!(
+            format_id_map(&mut buf, u32::MAX).unwrap(),
+            b"0 4294967295 1\n"
+        );
+    }
+
+    #[test]
+    fn maps_user_to_root() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "id -u; id -g; cat /proc/self/uid_map"]);
+        enter_on_exec(&mut command);
+        let output = match command.output() {
+            Ok(output) => output,
+            // Some kernels and sandboxes don't allow unprivileged user namespaces.
+            Err(e) => {
+                eprintln!("skipping: can't create a user namespace: {}", e);
+                return;
+            }
+        };
+        assert!(output.status.success(), "{:?}", output);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines = stdout.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "0");
+        assert_eq!(lines[1], "0");
+        let map = lines[2].split_whitespace().collect::<Vec<_>>();
+        assert_eq!(map, ["0", &getuid().to_string(), "1"]);
+    }
+}